@@ -0,0 +1,376 @@
+use std::fmt;
+
+/// The hash key under which an instance of a struct records the struct's name.
+pub const STRUCT_TYPE_KEY: &str = "__type";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+  pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockStatement {
+  pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+  Let(String, Expression),
+  /// Rebinds an existing variable, in whichever enclosing scope defines it.
+  Assign(String, Expression),
+  Return(Expression),
+  Expression(Expression),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+  Identifier(String),
+  IntegerLiteral(i64),
+  FloatLiteral(f64),
+  StringLiteral(String),
+  Boolean(bool),
+  ArrayLiteral(Vec<Expression>),
+  HashLiteral(Vec<(Expression, Expression)>),
+  Prefix(PrefixOperator, Box<Expression>),
+  Infix(InfixOperator, Box<Expression>, Box<Expression>),
+  If {
+    condition: Box<Expression>,
+    consequence: BlockStatement,
+    alternative: Option<BlockStatement>,
+  },
+  /// Runs the body for as long as the condition is truthy, and evaluates to null.
+  While {
+    condition: Box<Expression>,
+    body: BlockStatement,
+  },
+  /// A named literal, `fn name(...) { ... }`, can call itself by its name, whatever that name
+  /// is bound to where the function is defined.
+  FunctionLiteral {
+    name: Option<String>,
+    parameters: Vec<String>,
+    body: BlockStatement,
+  },
+  MacroLiteral {
+    parameters: Vec<String>,
+    body: BlockStatement,
+  },
+  Call {
+    function: Box<Expression>,
+    arguments: Vec<Expression>,
+  },
+  Index {
+    left: Box<Expression>,
+    index: Box<Expression>,
+  },
+  /// `left[start:end]`, where either bound may be left out.
+  Slice {
+    left: Box<Expression>,
+    start: Option<Box<Expression>>,
+    end: Option<Box<Expression>>,
+  },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PrefixOperator {
+  Bang,
+  Minus,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InfixOperator {
+  Plus,
+  Minus,
+  Asterisk,
+  /// Integer division truncates toward zero, unless a session is set to floor it.
+  Slash,
+  /// Division that rounds an integer quotient down, as `divmod` does. Floats divide as with `/`.
+  TildeSlash,
+  /// The remainder of truncating division, which takes the sign of the left operand.
+  Percent,
+  Ampersand,
+  Pipe,
+  Caret,
+  ShiftLeft,
+  /// An arithmetic shift, which keeps the sign of the left operand.
+  ShiftRight,
+  GT,
+  LT,
+  GtEq,
+  LtEq,
+  Eq,
+  NotEq,
+  /// `&&` and `||` only evaluate their right operand if the left doesn't settle the result, and
+  /// evaluate to a boolean.
+  And,
+  Or,
+}
+
+impl PrefixOperator {
+  pub fn symbol(&self) -> &'static str {
+    match self {
+      PrefixOperator::Bang => "!",
+      PrefixOperator::Minus => "-",
+    }
+  }
+}
+
+impl InfixOperator {
+  pub fn symbol(&self) -> &'static str {
+    match self {
+      InfixOperator::Plus => "+",
+      InfixOperator::Minus => "-",
+      InfixOperator::Asterisk => "*",
+      InfixOperator::Slash => "/",
+      InfixOperator::TildeSlash => "~/",
+      InfixOperator::Percent => "%",
+      InfixOperator::Ampersand => "&",
+      InfixOperator::Pipe => "|",
+      InfixOperator::Caret => "^",
+      InfixOperator::ShiftLeft => "<<",
+      InfixOperator::ShiftRight => ">>",
+      InfixOperator::GT => ">",
+      InfixOperator::LT => "<",
+      InfixOperator::GtEq => ">=",
+      InfixOperator::LtEq => "<=",
+      InfixOperator::Eq => "==",
+      InfixOperator::NotEq => "!=",
+      InfixOperator::And => "&&",
+      InfixOperator::Or => "||",
+    }
+  }
+}
+
+// Expressions print fully parenthesized, so the output shows how the source was grouped.
+
+impl fmt::Display for Program {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write_statements(f, &self.statements)
+  }
+}
+
+impl fmt::Display for BlockStatement {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.statements.is_empty() {
+      return write!(f, "{{}}");
+    }
+    write!(f, "{{ ")?;
+    write_statements(f, &self.statements)?;
+    write!(f, " }}")
+  }
+}
+
+fn write_statements(f: &mut fmt::Formatter, statements: &[Statement]) -> fmt::Result {
+  for (i, statement) in statements.iter().enumerate() {
+    if i > 0 {
+      write!(f, " ")?;
+    }
+    write!(f, "{}", statement)?;
+  }
+  Ok(())
+}
+
+impl fmt::Display for Statement {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Statement::Let(name, value) => write!(f, "let {} = {};", name, value),
+      Statement::Assign(name, value) => write!(f, "{} = {};", name, value),
+      Statement::Return(value) => write!(f, "return {};", value),
+      Statement::Expression(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl fmt::Display for Expression {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Expression::Identifier(name) => write!(f, "{}", name),
+      Expression::IntegerLiteral(i) => write!(f, "{}", i),
+      Expression::FloatLiteral(x) => write!(f, "{:?}", x),
+      Expression::StringLiteral(s) => write!(f, "\"{}\"", escape(s)),
+      Expression::Boolean(b) => write!(f, "{}", b),
+      Expression::ArrayLiteral(elements) => write!(f, "[{}]", join(elements)),
+      Expression::HashLiteral(pairs) => {
+        let pairs: Vec<String> = pairs.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+        write!(f, "{{{}}}", pairs.join(", "))
+      },
+      Expression::Prefix(operator, right) => write!(f, "({}{})", operator.symbol(), right),
+      Expression::Infix(operator, left, right) =>
+        write!(f, "({} {} {})", left, operator.symbol(), right),
+      Expression::If { condition, consequence, alternative } => {
+        write!(f, "if {} {}", condition, consequence)?;
+        if let Some(alternative) = alternative {
+          write!(f, " else {}", alternative)?;
+        }
+        Ok(())
+      },
+      Expression::While { condition, body } => write!(f, "while {} {}", condition, body),
+      Expression::FunctionLiteral { name, parameters, body } => {
+        write!(f, "fn")?;
+        if let Some(name) = name {
+          write!(f, " {}", name)?;
+        }
+        write!(f, "({}) {}", parameters.join(", "), body)
+      },
+      Expression::MacroLiteral { parameters, body } =>
+        write!(f, "macro({}) {}", parameters.join(", "), body),
+      Expression::Call { function, arguments } => write!(f, "{}({})", function, join(arguments)),
+      Expression::Index { left, index } => write!(f, "({}[{}])", left, index),
+      Expression::Slice { left, start, end } => {
+        write!(f, "({}[", left)?;
+        if let Some(start) = start {
+          write!(f, "{}", start)?;
+        }
+        write!(f, ":")?;
+        if let Some(end) = end {
+          write!(f, "{}", end)?;
+        }
+        write!(f, "])")
+      },
+    }
+  }
+}
+
+fn join(expressions: &[Expression]) -> String {
+  expressions.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+// The inverse of the escapes the lexer understands.
+pub(crate) fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\n' => escaped.push_str("\\n"),
+      '\t' => escaped.push_str("\\t"),
+      '"' => escaped.push_str("\\\""),
+      '\\' => escaped.push_str("\\\\"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Rebuilds `program` bottom-up: each expression is passed to `f` after its children have been.
+pub fn modify_program<E, F>(program: Program, f: &mut F) -> Result<Program, E>
+where F: FnMut(Expression) -> Result<Expression, E> {
+  Ok(Program { statements: modify_statements(program.statements, f)? })
+}
+
+/// Rebuilds `expression` bottom-up, as `modify_program` does.
+pub fn modify<E, F>(expression: Expression, f: &mut F) -> Result<Expression, E>
+where F: FnMut(Expression) -> Result<Expression, E> {
+  let modified = match expression {
+    Expression::Identifier(_) | Expression::IntegerLiteral(_) | Expression::FloatLiteral(_)
+      | Expression::StringLiteral(_) | Expression::Boolean(_) => expression,
+    Expression::ArrayLiteral(elements) => Expression::ArrayLiteral(modify_all(elements, f)?),
+    Expression::HashLiteral(pairs) => {
+      let mut modified = Vec::with_capacity(pairs.len());
+      for (k, v) in pairs {
+        modified.push((modify(k, f)?, modify(v, f)?));
+      }
+      Expression::HashLiteral(modified)
+    },
+    Expression::Prefix(operator, right) => Expression::Prefix(operator, Box::new(modify(*right, f)?)),
+    Expression::Infix(operator, left, right) => {
+      let left = modify(*left, f)?;
+      Expression::Infix(operator, Box::new(left), Box::new(modify(*right, f)?))
+    },
+    Expression::If { condition, consequence, alternative } => Expression::If {
+      condition: Box::new(modify(*condition, f)?),
+      consequence: modify_block(consequence, f)?,
+      alternative: match alternative {
+        Some(alternative) => Some(modify_block(alternative, f)?),
+        None => None,
+      },
+    },
+    Expression::While { condition, body } => Expression::While {
+      condition: Box::new(modify(*condition, f)?),
+      body: modify_block(body, f)?,
+    },
+    Expression::FunctionLiteral { name, parameters, body } =>
+      Expression::FunctionLiteral { name, parameters, body: modify_block(body, f)? },
+    Expression::MacroLiteral { parameters, body } =>
+      Expression::MacroLiteral { parameters, body: modify_block(body, f)? },
+    Expression::Call { function, arguments } => {
+      let function = Box::new(modify(*function, f)?);
+      Expression::Call { function, arguments: modify_all(arguments, f)? }
+    },
+    Expression::Index { left, index } => {
+      let left = Box::new(modify(*left, f)?);
+      Expression::Index { left, index: Box::new(modify(*index, f)?) }
+    },
+    Expression::Slice { left, start, end } => Expression::Slice {
+      left: Box::new(modify(*left, f)?),
+      start: match start {
+        Some(start) => Some(Box::new(modify(*start, f)?)),
+        None => None,
+      },
+      end: match end {
+        Some(end) => Some(Box::new(modify(*end, f)?)),
+        None => None,
+      },
+    },
+  };
+  f(modified)
+}
+
+fn modify_all<E, F>(expressions: Vec<Expression>, f: &mut F) -> Result<Vec<Expression>, E>
+where F: FnMut(Expression) -> Result<Expression, E> {
+  expressions.into_iter().map(|e| modify(e, f)).collect()
+}
+
+fn modify_block<E, F>(block: BlockStatement, f: &mut F) -> Result<BlockStatement, E>
+where F: FnMut(Expression) -> Result<Expression, E> {
+  Ok(BlockStatement { statements: modify_statements(block.statements, f)? })
+}
+
+fn modify_statements<E, F>(statements: Vec<Statement>, f: &mut F) -> Result<Vec<Statement>, E>
+where F: FnMut(Expression) -> Result<Expression, E> {
+  statements.into_iter().map(|statement| Ok(match statement {
+    Statement::Let(name, value) => Statement::Let(name, modify(value, f)?),
+    Statement::Assign(name, value) => Statement::Assign(name, modify(value, f)?),
+    Statement::Return(value) => Statement::Return(modify(value, f)?),
+    Statement::Expression(e) => Statement::Expression(modify(e, f)?),
+  })).collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap()
+  }
+
+  #[test]
+  fn test_display() {
+    let cases = [
+      ("let x = -a * b;", "let x = ((-a) * b);"),
+      ("x = x + 1", "x = (x + 1);"),
+      ("return a + b * c;", "return (a + (b * c));"),
+      ("if (x < y) { x } else { y; 1.0 }", "if (x < y) { x } else { y 1.0 }"),
+      ("fn(a, b) { a(b)[0] }; fn() {}", "fn(a, b) { (a(b)[0]) } fn() {}"),
+      ("while (!done) { step(); }", "while (!done) { step() }"),
+      ("s[1:n + 1]; s[:2]; s[1:]; s[:]", "(s[1:(n + 1)]) (s[:2]) (s[1:]) (s[:])"),
+      (r#"[1, {"a\tb": true}]"#, r#"[1, {"a\tb": true}]"#),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(parse(input).to_string(), *expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_modify() {
+    let mut one_to_two = |e| Ok::<_, ()>(match e {
+      Expression::IntegerLiteral(1) => Expression::IntegerLiteral(2),
+      e => e,
+    });
+    let program = parse("let a = [1, {1: 1}][1]; if (1) { return 1 } else { fn(x) { -1 }(1) }; while (1) {}");
+    let expected = parse("let a = [2, {2: 2}][2]; if (2) { return 2 } else { fn(x) { -2 }(2) }; while (2) {}");
+    assert_eq!(modify_program(program, &mut one_to_two), Ok(expected));
+    let program = parse("a[1:][:1]");
+    let expected = parse("a[2:][:2]");
+    assert_eq!(modify_program(program, &mut one_to_two), Ok(expected));
+  }
+}
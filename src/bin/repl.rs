@@ -1,3 +0,0 @@
-fn main() {
-  println!("I'm going to be a REPL someday!");
-}
\ No newline at end of file
@@ -0,0 +1,418 @@
+use crate::ast::STRUCT_TYPE_KEY;
+use crate::object::{BuiltinFunction, HashKey, HashPair, Object};
+use crate::observer;
+
+pub struct BuiltinSpec {
+  pub name: &'static str,
+  /// Parameter names, used for arity checks in suggestions and for display; `None` for
+  /// builtins taking any number of arguments.
+  pub parameters: Option<&'static [&'static str]>,
+  /// Whether calling the builtin has no effect beyond its result, so that discarding the
+  /// result is almost certainly a mistake.
+  pub pure: bool,
+  pub function: BuiltinFunction,
+}
+
+impl BuiltinSpec {
+  pub fn accepts(&self, arity: usize) -> bool {
+    self.parameters.is_none_or(|p| p.len() == arity)
+  }
+
+  pub fn signature(&self) -> String {
+    match self.parameters {
+      Some(p) => format!("{}({})", self.name, p.join(", ")),
+      None => format!("{}(...)", self.name),
+    }
+  }
+}
+
+pub const BUILTINS: &[BuiltinSpec] = &[
+  BuiltinSpec { name: "len", parameters: Some(&["x"]), pure: true, function: len },
+  BuiltinSpec { name: "first", parameters: Some(&["arr"]), pure: true, function: first },
+  BuiltinSpec { name: "last", parameters: Some(&["arr"]), pure: true, function: last },
+  BuiltinSpec { name: "rest", parameters: Some(&["arr"]), pure: true, function: rest },
+  BuiltinSpec { name: "push", parameters: Some(&["arr", "x"]), pure: true, function: push },
+  BuiltinSpec { name: "puts", parameters: None, pure: false, function: puts },
+  BuiltinSpec { name: "divmod", parameters: Some(&["a", "b"]), pure: true, function: divmod },
+  BuiltinSpec { name: "split", parameters: Some(&["s", "sep"]), pure: true, function: split },
+  BuiltinSpec { name: "join", parameters: Some(&["arr", "sep"]), pure: true, function: join },
+  BuiltinSpec { name: "contains", parameters: Some(&["x", "item"]), pure: true, function: contains },
+  BuiltinSpec { name: "to_upper", parameters: Some(&["s"]), pure: true, function: to_upper },
+  BuiltinSpec { name: "to_lower", parameters: Some(&["s"]), pure: true, function: to_lower },
+  BuiltinSpec { name: "trim", parameters: Some(&["s"]), pure: true, function: trim },
+  BuiltinSpec { name: "chars", parameters: Some(&["s"]), pure: true, function: chars },
+  BuiltinSpec { name: "type", parameters: Some(&["x"]), pure: true, function: type_of },
+  BuiltinSpec { name: "is_a", parameters: Some(&["x", "type"]), pure: true, function: is_a },
+];
+
+pub fn spec(name: &str) -> Option<&'static BuiltinSpec> {
+  BUILTINS.iter().find(|b| b.name == name)
+}
+
+/// Looks up one of the standard builtins. These are consulted only after the environment, so
+/// a binding or a builtin registered with `Environment::register_builtin` takes precedence.
+pub fn lookup(name: &str) -> Option<Object> {
+  spec(name).map(|b| Object::Builtin { name: String::from(b.name), function: b.function.into() })
+}
+
+pub(crate) fn check_arity(args: &[Object], want: usize) -> Result<(), Object> {
+  if args.len() == want {
+    Ok(())
+  } else {
+    Err(Object::Error(format!("wrong number of arguments. got={}, want={}", args.len(), want)))
+  }
+}
+
+fn array_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a Vec<Object>, Object> {
+  match arg {
+    Object::Array(elements) => Ok(elements),
+    other => Err(Object::Error(format!(
+      "argument to `{}` must be ARRAY, got {}", name, other.type_name()
+    ))),
+  }
+}
+
+fn string_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a str, Object> {
+  match arg {
+    Object::Str(s) => Ok(s),
+    other => Err(Object::Error(format!(
+      "argument to `{}` must be STRING, got {}", name, other.type_name()
+    ))),
+  }
+}
+
+fn strings(elements: Vec<String>) -> Object {
+  Object::Array(elements.into_iter().map(Object::Str).collect())
+}
+
+fn len(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match &args[0] {
+    Object::Str(s) => Object::Integer(s.chars().count() as i64),
+    Object::Array(elements) => Object::Integer(elements.len() as i64),
+    other => Object::Error(format!("argument to `len` not supported, got {}", other.type_name())),
+  }
+}
+
+fn first(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match array_arg("first", &args[0]) {
+    Ok(elements) => elements.first().cloned().unwrap_or(Object::Null),
+    Err(e) => e,
+  }
+}
+
+fn last(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match array_arg("last", &args[0]) {
+    Ok(elements) => elements.last().cloned().unwrap_or(Object::Null),
+    Err(e) => e,
+  }
+}
+
+fn rest(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match array_arg("rest", &args[0]) {
+    Ok(elements) if elements.is_empty() => Object::Null,
+    Ok(elements) => Object::Array(elements[1..].to_vec()),
+    Err(e) => e,
+  }
+}
+
+fn push(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  match array_arg("push", &args[0]) {
+    Ok(elements) => {
+      let mut elements = elements.clone();
+      elements.push(args[1].clone());
+      Object::Array(elements)
+    },
+    Err(e) => e,
+  }
+}
+
+fn puts(args: Vec<Object>) -> Object {
+  for arg in args {
+    observer::output(&arg.to_string());
+  }
+  Object::Null
+}
+
+// `/` and `%` truncate toward zero, as Rust's do; divmod floors as `~/` does, so that the remainder
+// takes the sign of the divisor, as Python's `//` and `%` do.
+fn divmod(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  let (a, b) = match (&args[0], &args[1]) {
+    (Object::Integer(a), Object::Integer(b)) => (*a, *b),
+    (a, b) => return Object::Error(format!(
+      "arguments to `divmod` must be INTEGER, got {} and {}", a.type_name(), b.type_name()
+    )),
+  };
+  if b == 0 {
+    return Object::Error(String::from("division by zero"));
+  }
+  let (mut quotient, mut remainder) = match (a.checked_div(b), a.checked_rem(b)) {
+    (Some(q), Some(r)) => (q, r),
+    _ => return Object::Error(format!("integer overflow: divmod({}, {})", a, b)),
+  };
+  if remainder != 0 && (remainder < 0) != (b < 0) {
+    quotient -= 1;
+    remainder += b;
+  }
+  Object::Array(vec!(Object::Integer(quotient), Object::Integer(remainder)))
+}
+
+fn split(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  match (string_arg("split", &args[0]), string_arg("split", &args[1])) {
+    (Ok(_), Ok("")) => Object::Error(String::from("separator given to `split` must not be empty")),
+    (Ok(s), Ok(separator)) => strings(s.split(separator).map(String::from).collect()),
+    (Err(e), _) | (_, Err(e)) => e,
+  }
+}
+
+fn join(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  let (elements, separator) = match (array_arg("join", &args[0]), string_arg("join", &args[1])) {
+    (Ok(elements), Ok(separator)) => (elements, separator),
+    (Err(e), _) | (_, Err(e)) => return e,
+  };
+  let mut parts = vec!();
+  for element in elements {
+    match element {
+      Object::Str(s) => parts.push(s.as_str()),
+      other => return Object::Error(format!(
+        "elements joined by `join` must be STRING, got {}", other.type_name()
+      )),
+    }
+  }
+  Object::Str(parts.join(separator))
+}
+
+// Whether a string contains a substring, or an array an element equal to the item.
+fn contains(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  match (&args[0], &args[1]) {
+    (Object::Str(s), item) => match string_arg("contains", item) {
+      Ok(substring) => Object::Boolean(s.contains(substring)),
+      Err(e) => e,
+    },
+    (Object::Array(elements), item) => Object::Boolean(elements.contains(item)),
+    (other, _) => Object::Error(format!(
+      "argument to `contains` must be STRING or ARRAY, got {}", other.type_name()
+    )),
+  }
+}
+
+fn to_upper(args: Vec<Object>) -> Object {
+  map_string("to_upper", args, str::to_uppercase)
+}
+
+fn to_lower(args: Vec<Object>) -> Object {
+  map_string("to_lower", args, str::to_lowercase)
+}
+
+fn trim(args: Vec<Object>) -> Object {
+  map_string("trim", args, |s| String::from(s.trim()))
+}
+
+fn chars(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match string_arg("chars", &args[0]) {
+    Ok(s) => strings(s.chars().map(String::from).collect()),
+    Err(e) => e,
+  }
+}
+
+// The name of the struct an instance was made by, or else the name of the object's type.
+fn struct_type(object: &Object) -> &str {
+  if let Object::Hash(pairs) = object {
+    let tag = pairs.get(&HashKey::Str(String::from(STRUCT_TYPE_KEY)));
+    if let Some(HashPair { value: Object::Str(name), .. }) = tag {
+      return name;
+    }
+  }
+  object.type_name()
+}
+
+fn type_of(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  Object::Str(String::from(struct_type(&args[0])))
+}
+
+fn is_a(args: Vec<Object>) -> Object {
+  if let Err(e) = check_arity(&args, 2) {
+    return e;
+  }
+  match string_arg("is_a", &args[1]) {
+    Ok(name) => Object::Boolean(struct_type(&args[0]) == name),
+    Err(e) => e,
+  }
+}
+
+fn map_string(name: &str, args: Vec<Object>, f: fn(&str) -> String) -> Object {
+  if let Err(e) = check_arity(&args, 1) {
+    return e;
+  }
+  match string_arg(name, &args[0]) {
+    Ok(s) => Object::Str(f(s)),
+    Err(e) => e,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::error::MonkeyError;
+  use crate::eval::eval;
+  use crate::lexer::Lexer;
+  use crate::object::Environment;
+  use crate::parser::Parser;
+
+  fn run(input: &str) -> Object {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    match eval(&program, &Environment::new()) {
+      Ok(value) => value,
+      Err(MonkeyError::Runtime { message, .. }) => Object::Error(message),
+      Err(e) => panic!("evaluating {:?} failed with {}", input, e),
+    }
+  }
+
+  fn error(message: &str) -> Object {
+    Object::Error(String::from(message))
+  }
+
+  fn array(elements: &[i64]) -> Object {
+    Object::Array(elements.iter().map(|i| Object::Integer(*i)).collect())
+  }
+
+  #[test]
+  fn test_builtins() {
+    let cases = [
+      (r#"len("")"#, Object::Integer(0)),
+      (r#"len("four")"#, Object::Integer(4)),
+      ("len([1, 2, 3])", Object::Integer(3)),
+      ("len(1)", error("argument to `len` not supported, got INTEGER")),
+      (r#"len("one", "two")"#, error("wrong number of arguments. got=2, want=1")),
+      ("first([1, 2, 3])", Object::Integer(1)),
+      ("first([])", Object::Null),
+      ("first(1)", error("argument to `first` must be ARRAY, got INTEGER")),
+      ("last([1, 2, 3])", Object::Integer(3)),
+      ("last([])", Object::Null),
+      ("rest([1, 2, 3])", array(&[2, 3])),
+      ("rest([])", Object::Null),
+      ("let a = [1]; let b = push(a, 2); a", array(&[1])),
+      ("push([1], 2)", array(&[1, 2])),
+      ("push(1, 1)", error("argument to `push` must be ARRAY, got INTEGER")),
+      (r#"puts("hello", 1)"#, Object::Null),
+      ("divmod(7, 2)", array(&[3, 1])),
+      ("divmod(-7, 2)", array(&[-4, 1])),
+      ("divmod(7, -2)", array(&[-4, -1])),
+      ("divmod(-7, -2)", array(&[3, -1])),
+      ("divmod(-6, 3)", array(&[-2, 0])),
+      ("[-7 / 2, -7 % 2]", array(&[-3, -1])),
+      ("divmod(1, 0)", error("division by zero")),
+      ("let min = -9223372036854775807 - 1; divmod(min, -1)", error(
+        "integer overflow: divmod(-9223372036854775808, -1)"
+      )),
+      ("divmod(1.5, 1)", error("arguments to `divmod` must be INTEGER, got FLOAT and INTEGER")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_structs() {
+    let define = "let Point = struct { x, y }; let p = Point(1, 2);";
+    let cases = [
+      ("p", r#"{"__type": "Point", "x": 1, "y": 2}"#),
+      (r#"p["y"]"#, "2"),
+      ("type(p)", r#""Point""#),
+      (r#"[is_a(p, "Point"), is_a(p, "HASH"), is_a({}, "HASH"), is_a(1, "Point")]"#, "[true, false, true, false]"),
+      (r#"[type(1), type({"__type": 1})]"#, r#"["INTEGER", "HASH"]"#),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(run(&format!("{} {}", define, input)), run(expected), "input {:?}", input);
+    }
+    assert_eq!(run("let Empty = struct {}; type(Empty())"), run(r#""Empty""#));
+    assert_eq!(run("is_a(1, 2)"), error("argument to `is_a` must be STRING, got INTEGER"));
+  }
+
+  #[test]
+  fn test_string_builtins() {
+    let cases = [
+      (r#"split("a,b,,c", ",")"#, r#"["a", "b", "", "c"]"#),
+      (r#"split("no separator", ";")"#, r#"["no separator"]"#),
+      (r#"join(["a", "b", "c"], ", ")"#, r#""a, b, c""#),
+      (r#"join([], "-")"#, r#""""#),
+      (r#"join(split("1 2 3", " "), "+")"#, r#""1+2+3""#),
+      (r#"contains("monkey", "key")"#, "true"),
+      (r#"contains("monkey", "ape")"#, "false"),
+      (r#"contains([1, "two", 3], "two")"#, "true"),
+      (r#"to_upper("Monkey")"#, r#""MONKEY""#),
+      (r#"to_lower("Monkey")"#, r#""monkey""#),
+      (r#"trim("  	padded 
+")"#, r#""padded""#),
+      (r#"chars("héllo")"#, r#"["h", "é", "l", "l", "o"]"#),
+      (r#"chars("")"#, "[]"),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(run(input), run(expected), "input {:?}", input);
+    }
+
+    let errors = [
+      (r#"split("abc", "")"#, "separator given to `split` must not be empty"),
+      (r#"split(1, ",")"#, "argument to `split` must be STRING, got INTEGER"),
+      (r#"join([1, 2], ",")"#, "elements joined by `join` must be STRING, got INTEGER"),
+      (r#"join("ab", ",")"#, "argument to `join` must be ARRAY, got STRING"),
+      (r#"contains("abc", 1)"#, "argument to `contains` must be STRING, got INTEGER"),
+      (r#"contains(1, 1)"#, "argument to `contains` must be STRING or ARRAY, got INTEGER"),
+      ("to_upper([])", "argument to `to_upper` must be STRING, got ARRAY"),
+      (r#"trim("a", "b")"#, "wrong number of arguments. got=2, want=1"),
+    ];
+    for (input, expected) in errors.iter() {
+      assert_eq!(run(input), error(expected), "input {:?}", input);
+    }
+  }
+
+  fn double(args: Vec<Object>) -> Object {
+    match args.as_slice() {
+      [Object::Integer(i)] => Object::Integer(i * 2),
+      _ => error("double takes one integer"),
+    }
+  }
+
+  #[test]
+  fn test_register_builtin() {
+    let env = Environment::new();
+    env.borrow_mut().register_builtin("double", double);
+    env.borrow_mut().register_builtin("len", double);
+    let by = 3;
+    env.borrow_mut().register_fn("times", move |x: i64| Ok(x * by));
+    let program = Parser::new(Lexer::new("double(4) + len(1) + times(2)")).parse_program().unwrap();
+    assert_eq!(eval(&program, &env), Ok(Object::Integer(16)));
+  }
+}
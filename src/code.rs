@@ -0,0 +1,250 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u8)]
+pub enum OpCode {
+  Constant,
+  Pop,
+  True,
+  False,
+  Null,
+  Add,
+  Sub,
+  Mul,
+  Div,
+  FloorDiv,
+  Mod,
+  BitAnd,
+  BitOr,
+  BitXor,
+  ShiftLeft,
+  ShiftRight,
+  Equal,
+  NotEqual,
+  GreaterThan,
+  LessThan,
+  GreaterEqual,
+  LessEqual,
+  Minus,
+  Bang,
+  JumpNotTruthy,
+  Jump,
+  GetGlobal,
+  SetGlobal,
+  GetLocal,
+  SetLocal,
+  GetBuiltin,
+  GetFree,
+  CurrentClosure,
+  Array,
+  Hash,
+  Index,
+  Slice,
+  Call,
+  ReturnValue,
+  Return,
+  Closure,
+}
+
+// Indexed by opcode byte, so this must list the variants in declaration order.
+const OPCODES: &[OpCode] = &[
+  OpCode::Constant,
+  OpCode::Pop,
+  OpCode::True,
+  OpCode::False,
+  OpCode::Null,
+  OpCode::Add,
+  OpCode::Sub,
+  OpCode::Mul,
+  OpCode::Div,
+  OpCode::FloorDiv,
+  OpCode::Mod,
+  OpCode::BitAnd,
+  OpCode::BitOr,
+  OpCode::BitXor,
+  OpCode::ShiftLeft,
+  OpCode::ShiftRight,
+  OpCode::Equal,
+  OpCode::NotEqual,
+  OpCode::GreaterThan,
+  OpCode::LessThan,
+  OpCode::GreaterEqual,
+  OpCode::LessEqual,
+  OpCode::Minus,
+  OpCode::Bang,
+  OpCode::JumpNotTruthy,
+  OpCode::Jump,
+  OpCode::GetGlobal,
+  OpCode::SetGlobal,
+  OpCode::GetLocal,
+  OpCode::SetLocal,
+  OpCode::GetBuiltin,
+  OpCode::GetFree,
+  OpCode::CurrentClosure,
+  OpCode::Array,
+  OpCode::Hash,
+  OpCode::Index,
+  OpCode::Slice,
+  OpCode::Call,
+  OpCode::ReturnValue,
+  OpCode::Return,
+  OpCode::Closure,
+];
+
+impl OpCode {
+  pub fn from_byte(byte: u8) -> Option<OpCode> {
+    OPCODES.get(byte as usize).copied()
+  }
+
+  /// The width in bytes of each of the opcode's operands, which are stored big-endian.
+  pub fn operand_widths(self) -> &'static [usize] {
+    match self {
+      OpCode::Constant
+        | OpCode::JumpNotTruthy
+        | OpCode::Jump
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Array
+        | OpCode::Hash => &[2],
+      OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetBuiltin
+        | OpCode::GetFree
+        | OpCode::Call => &[1],
+      OpCode::Closure => &[2, 1],
+      _ => &[],
+    }
+  }
+
+  pub fn name(self) -> String {
+    format!("Op{:?}", self)
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Instructions(pub Vec<u8>);
+
+impl Instructions {
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+impl From<Vec<Vec<u8>>> for Instructions {
+  fn from(instructions: Vec<Vec<u8>>) -> Self {
+    Instructions(instructions.concat())
+  }
+}
+
+impl fmt::Display for Instructions {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut i = 0;
+    while i < self.0.len() {
+      let op = match OpCode::from_byte(self.0[i]) {
+        Some(op) => op,
+        None => {
+          writeln!(f, "{:04} ERROR: unknown opcode {}", i, self.0[i])?;
+          i += 1;
+          continue;
+        },
+      };
+      let (operands, read) = read_operands(op, &self.0[i + 1..]);
+      write!(f, "{:04} {}", i, op.name())?;
+      for operand in operands {
+        write!(f, " {}", operand)?;
+      }
+      writeln!(f)?;
+      i += 1 + read;
+    }
+    Ok(())
+  }
+}
+
+/// Encodes a single instruction. Operands must fit their widths; callers check this first.
+pub fn make(op: OpCode, operands: &[usize]) -> Vec<u8> {
+  let mut instruction = vec!(op as u8);
+  for (operand, width) in operands.iter().zip(op.operand_widths()) {
+    match width {
+      2 => instruction.extend_from_slice(&(*operand as u16).to_be_bytes()),
+      1 => instruction.push(*operand as u8),
+      _ => unreachable!("unsupported operand width {}", width),
+    }
+  }
+  instruction
+}
+
+/// Decodes the operands following an opcode, returning them and the number of bytes read.
+pub fn read_operands(op: OpCode, ins: &[u8]) -> (Vec<usize>, usize) {
+  let mut operands = vec!();
+  let mut offset = 0;
+  for width in op.operand_widths() {
+    match width {
+      2 => operands.push(read_u16(ins, offset) as usize),
+      1 => operands.push(ins[offset] as usize),
+      _ => unreachable!("unsupported operand width {}", width),
+    }
+    offset += width;
+  }
+  (operands, offset)
+}
+
+pub fn read_u16(ins: &[u8], offset: usize) -> u16 {
+  u16::from_be_bytes([ins[offset], ins[offset + 1]])
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_opcode_bytes_round_trip() {
+    for (i, op) in OPCODES.iter().enumerate() {
+      assert_eq!(*op as usize, i);
+      assert_eq!(OpCode::from_byte(i as u8), Some(*op));
+    }
+    assert_eq!(OpCode::from_byte(OPCODES.len() as u8), None);
+  }
+
+  #[test]
+  fn test_make() {
+    assert_eq!(make(OpCode::Constant, &[65534]), vec!(OpCode::Constant as u8, 255, 254));
+    assert_eq!(make(OpCode::Add, &[]), vec!(OpCode::Add as u8));
+    assert_eq!(make(OpCode::GetLocal, &[255]), vec!(OpCode::GetLocal as u8, 255));
+    assert_eq!(make(OpCode::Closure, &[65534, 255]), vec!(OpCode::Closure as u8, 255, 254, 255));
+  }
+
+  #[test]
+  fn test_read_operands() {
+    let cases: &[(OpCode, &[usize], usize)] = &[
+      (OpCode::Constant, &[65535], 2),
+      (OpCode::GetLocal, &[255], 1),
+      (OpCode::Closure, &[65535, 255], 3),
+    ];
+    for (op, operands, bytes) in cases {
+      let instruction = make(*op, operands);
+      assert_eq!(read_operands(*op, &instruction[1..]), (operands.to_vec(), *bytes));
+    }
+  }
+
+  #[test]
+  fn test_instructions_display() {
+    let instructions = Instructions::from(vec!(
+      make(OpCode::Add, &[]),
+      make(OpCode::GetLocal, &[1]),
+      make(OpCode::Constant, &[2]),
+      make(OpCode::Constant, &[65535]),
+      make(OpCode::Closure, &[65535, 255]),
+    ));
+    assert_eq!(instructions.to_string(), "\
+0000 OpAdd
+0001 OpGetLocal 1
+0003 OpConstant 2
+0006 OpConstant 65535
+0009 OpClosure 65535 255
+");
+  }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+
+/// A longer description of a diagnostic, looked up by the stable code it is reported with.
+#[derive(Debug, PartialEq)]
+pub struct Explanation {
+  pub code: &'static str,
+  pub title: &'static str,
+  pub description: &'static str,
+  pub example: &'static str,
+  pub fix: &'static str,
+}
+
+impl fmt::Display for Explanation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "{}: {}", self.code, self.title)?;
+    writeln!(f)?;
+    writeln!(f, "{}", self.description)?;
+    writeln!(f)?;
+    writeln!(f, "Erroneous code example:")?;
+    writeln!(f)?;
+    writeln!(f, "    {}", self.example)?;
+    writeln!(f)?;
+    writeln!(f, "Corrected:")?;
+    writeln!(f)?;
+    write!(f, "    {}", self.fix)
+  }
+}
+
+pub const EXPLANATIONS: &[Explanation] = &[
+  Explanation {
+    code: "E0001",
+    title: "unexpected token",
+    description: "\
+The parser needed a particular token to continue, such as the `=` in a `let` statement or the \
+closing `)` of a call, and found something else. This is often a missing delimiter or a \
+delimiter left unclosed at the end of the input.",
+    example: "let x 5;",
+    fix: "let x = 5;",
+  },
+  Explanation {
+    code: "E0002",
+    title: "expected an identifier",
+    description: "\
+A name was required, for instance after `let` or in a function's parameter list, but the next \
+token was not an identifier. Keywords such as `fn` or `if` cannot be used as names.",
+    example: "let if = 5;",
+    fix: "let cond = 5;",
+  },
+  Explanation {
+    code: "E0003",
+    title: "token cannot start an expression",
+    description: "\
+An expression was expected, but the token found cannot begin one. This usually means an operand \
+is missing, for example on one side of an operator or after `return`.",
+    example: "let x = 1 + ;",
+    fix: "let x = 1 + 2;",
+  },
+];
+
+pub fn explain(code: &str) -> Option<&'static Explanation> {
+  EXPLANATIONS.iter().find(|e| e.code.eq_ignore_ascii_case(code))
+}
+
+/// The Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = diagonal + if ca == *cb { 0 } else { 1 };
+      diagonal = row[j + 1];
+      row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+    }
+  }
+  row[b.len()]
+}
+
+/// Picks the candidate closest to `name`, if any is close enough to plausibly be a typo.
+/// Candidates are `(name, label)` pairs; the label of the winner is returned.
+pub fn suggest<I: IntoIterator<Item = (String, String)>>(name: &str, candidates: I) -> Option<String> {
+  let max_distance = name.chars().count().div_ceil(3).max(1);
+  candidates.into_iter()
+    .map(|(candidate, label)| (edit_distance(name, &candidate), label))
+    .filter(|(distance, _)| *distance <= max_distance)
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, label)| label)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_explain() {
+    assert_eq!(explain("E0002").map(|e| e.title), Some("expected an identifier"));
+    assert_eq!(explain("e0003").map(|e| e.code), Some("E0003"));
+    assert_eq!(explain("E9999"), None);
+  }
+
+  #[test]
+  fn test_edit_distance_and_suggest() {
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("push", "push"), 0);
+
+    let candidates = || vec!(
+      (String::from("push"), String::from("push(arr, x)")),
+      (String::from("puts"), String::from("puts(...)")),
+    );
+    assert_eq!(suggest("pussh", candidates()), Some(String::from("push(arr, x)")));
+    assert_eq!(suggest("putz", candidates()), Some(String::from("puts(...)")));
+    assert_eq!(suggest("length", candidates()), None);
+  }
+}
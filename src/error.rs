@@ -0,0 +1,134 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+
+use crate::compiler::CompileError;
+use crate::lexer::{LexError, Span};
+use crate::parser::ParseError;
+use crate::vm::VmError;
+
+/// Any way that running Monkey source can fail.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MonkeyError {
+  /// Every error the lexer and the parser found, as both carry on past the first. Parse errors
+  /// at an illegal token are left out, as the lex error there already says what is wrong.
+  Syntax { lex: Vec<LexError>, parse: Vec<ParseError> },
+  Compile(CompileError),
+  /// An error raised while expanding macros or running the program. Neither engine tracks
+  /// positions within a statement, so the most a run can say is where the top-level statement
+  /// it stopped in starts, when it knows where each one does.
+  Runtime { message: String, span: Option<Span> },
+}
+
+impl MonkeyError {
+  /// A runtime error with no known location.
+  pub fn runtime(message: String) -> Self {
+    MonkeyError::Runtime { message, span: None }
+  }
+
+  // The syntax errors in the order they appear in the source.
+  fn syntax_errors<'a>(
+    lex: &'a [LexError], parse: &'a [ParseError],
+  ) -> Vec<(Span, &'a (dyn Error + 'static))> {
+    let mut errors: Vec<(Span, &(dyn Error + 'static))> = lex.iter()
+      .map(|e| (e.span, e as &(dyn Error + 'static)))
+      .chain(parse.iter().map(|e| (e.span, e as &(dyn Error + 'static))))
+      .collect();
+    errors.sort_by_key(|(span, _)| span.byte_offset);
+    errors
+  }
+}
+
+impl fmt::Display for MonkeyError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      MonkeyError::Syntax { lex, parse } => {
+        let errors: Vec<String> = MonkeyError::syntax_errors(lex, parse).iter()
+          .map(|(_, e)| e.to_string())
+          .collect();
+        write!(f, "{}", errors.join("\n"))
+      },
+      MonkeyError::Compile(e) => write!(f, "compile error: {}", e),
+      MonkeyError::Runtime { message, span: Some(span) } =>
+        write!(f, "{}:{}: error: {}", span.line, span.column, message),
+      MonkeyError::Runtime { message, span: None } => write!(f, "error: {}", message),
+    }
+  }
+}
+
+impl Error for MonkeyError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      MonkeyError::Syntax { lex, parse } =>
+        MonkeyError::syntax_errors(lex, parse).first().map(|(_, e)| *e),
+      MonkeyError::Compile(e) => Some(e),
+      MonkeyError::Runtime { .. } => None,
+    }
+  }
+}
+
+impl From<LexError> for MonkeyError {
+  fn from(e: LexError) -> Self {
+    MonkeyError::Syntax { lex: vec!(e), parse: vec!() }
+  }
+}
+
+impl From<Vec<ParseError>> for MonkeyError {
+  fn from(errors: Vec<ParseError>) -> Self {
+    MonkeyError::Syntax { lex: vec!(), parse: errors }
+  }
+}
+
+impl From<CompileError> for MonkeyError {
+  fn from(e: CompileError) -> Self {
+    MonkeyError::Compile(e)
+  }
+}
+
+// Lets conversions that can't fail, such as an `Object` to itself, stand in for ones that can.
+impl From<Infallible> for MonkeyError {
+  fn from(e: Infallible) -> Self {
+    match e {}
+  }
+}
+
+impl From<VmError> for MonkeyError {
+  fn from(e: VmError) -> Self {
+    MonkeyError::runtime(e.0)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::parser;
+
+  #[test]
+  fn test_parse_errors() {
+    let error = parser::parse("let x = \"open").unwrap_err();
+    assert_eq!(error.to_string(), "1:9: error: unterminated string");
+    assert!(matches!(&error, MonkeyError::Syntax { lex, .. } if lex[0].span.column == 9));
+
+    let error = parser::parse("let = 1;\nlet y 2;").unwrap_err();
+    assert_eq!(error.to_string(), "\
+1:5: error[E0002]: expected next token to be an identifier, got Assign instead
+2:7: error[E0001]: expected next token to be Assign, got Int(2) instead");
+    let first = "1:5: error[E0002]: expected next token to be an identifier, got Assign instead";
+    assert_eq!(error.source().unwrap().to_string(), first);
+
+    // lex errors don't hide each other, or the parse errors elsewhere
+    let error = parser::parse("1 +;\n@\nlet y = #;").unwrap_err();
+    assert_eq!(error.to_string(), "\
+1:4: error[E0003]: no prefix parse function for Semicolon found
+2:1: error: unexpected character '@'
+3:9: error: unexpected character '#'");
+  }
+
+  #[test]
+  fn test_runtime_errors() {
+    let span = Span { line: 2, column: 3, byte_offset: 7 };
+    let error = MonkeyError::Runtime { message: String::from("division by zero"), span: Some(span) };
+    assert_eq!(error.to_string(), "2:3: error: division by zero");
+    assert_eq!(MonkeyError::runtime(String::from("no")).to_string(), "error: no");
+  }
+}
@@ -0,0 +1,884 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{self, *};
+use crate::builtins;
+use crate::diagnostics;
+use crate::error::MonkeyError;
+use crate::object::{Env, Environment, HashPair, Object};
+use crate::observer;
+
+/// Runs a program in `env`, returning the value of its last statement or of a top-level
+/// `return`.
+pub fn eval(program: &Program, env: &Env) -> Result<Object, MonkeyError> {
+  eval_program(program, env).map_err(|(_, e)| e)
+}
+
+// Like `eval`, but an error comes with the index of the top-level statement it stopped in.
+pub(crate) fn eval_program(program: &Program, env: &Env) -> Result<Object, (usize, MonkeyError)> {
+  let mut result = Object::Null;
+  for (i, statement) in program.statements.iter().enumerate() {
+    observer::statement(i);
+    result = eval_statement(statement, env);
+    match result {
+      Object::ReturnValue(value) => return Ok(*value),
+      Object::Error(message) => return Err((i, MonkeyError::runtime(message))),
+      _ => {},
+    }
+  }
+  Ok(result)
+}
+
+// Unlike `eval`, this leaves return values wrapped so they propagate out of nested blocks.
+fn eval_block_statement(block: &BlockStatement, env: &Env) -> Object {
+  let mut result = Object::Null;
+  for statement in &block.statements {
+    result = eval_statement(statement, env);
+    if let Object::ReturnValue(_) | Object::Error(_) = result {
+      return result;
+    }
+  }
+  result
+}
+
+fn eval_statement(statement: &Statement, env: &Env) -> Object {
+  match statement {
+    Statement::Expression(e) => eval_expression(e, env),
+    Statement::Return(e) => {
+      let value = eval_expression(e, env);
+      if value.is_error() {
+        return value;
+      }
+      Object::ReturnValue(Box::new(value))
+    },
+    Statement::Let(name, e) => {
+      let value = eval_expression(e, env);
+      if value.is_error() {
+        return value;
+      }
+      env.borrow_mut().set(name, value);
+      Object::Null
+    },
+    Statement::Assign(name, e) => {
+      let value = eval_expression(e, env);
+      if value.is_error() {
+        return value;
+      }
+      if !env.borrow_mut().set_existing(name, value) {
+        return Object::Error(format!("cannot assign to undeclared identifier: {}", name));
+      }
+      Object::Null
+    },
+  }
+}
+
+fn eval_expression(expression: &Expression, env: &Env) -> Object {
+  match expression {
+    Expression::IntegerLiteral(i) => Object::Integer(*i),
+    Expression::FloatLiteral(x) => Object::Float(*x),
+    Expression::StringLiteral(s) => Object::Str(s.clone()),
+    Expression::Boolean(b) => Object::Boolean(*b),
+    Expression::ArrayLiteral(elements) => match eval_expressions(elements, env) {
+      Ok(elements) => Object::Array(elements),
+      Err(e) => e,
+    },
+    Expression::HashLiteral(pairs) => eval_hash_literal(pairs, env),
+    Expression::Identifier(name) => match lookup_identifier(name, env) {
+      Some(value) => value,
+      None => Object::Error(format!("identifier not found: {}", name)),
+    },
+    Expression::Prefix(operator, right) => {
+      let right = eval_expression(right, env);
+      if right.is_error() {
+        return right;
+      }
+      eval_prefix_expression(*operator, right)
+    },
+    Expression::Infix(operator, left, right) => {
+      let left = eval_expression(left, env);
+      if left.is_error() {
+        return left;
+      }
+      match (operator, is_truthy(&left)) {
+        (InfixOperator::And, false) => return Object::Boolean(false),
+        (InfixOperator::Or, true) => return Object::Boolean(true),
+        _ => {},
+      }
+      let right = eval_expression(right, env);
+      if right.is_error() {
+        return right;
+      }
+      eval_infix_expression(*operator, left, right)
+    },
+    Expression::If { condition, consequence, alternative } => {
+      let condition = eval_expression(condition, env);
+      if condition.is_error() {
+        return condition;
+      }
+      if is_truthy(&condition) {
+        eval_block_statement(consequence, env)
+      } else if let Some(alternative) = alternative {
+        eval_block_statement(alternative, env)
+      } else {
+        Object::Null
+      }
+    },
+    Expression::While { condition, body } => {
+      loop {
+        let condition = eval_expression(condition, env);
+        if condition.is_error() {
+          return condition;
+        }
+        if !is_truthy(&condition) {
+          return Object::Null;
+        }
+        let result = eval_block_statement(body, env);
+        if let Object::ReturnValue(_) | Object::Error(_) = result {
+          return result;
+        }
+      }
+    },
+    Expression::FunctionLiteral { name, parameters, body } => {
+      // a named function sees itself in an environment of its own, between it and its definer's
+      let env = match name {
+        Some(_) => Environment::new_enclosed(env),
+        None => Rc::clone(env),
+      };
+      let function = Object::Function {
+        parameters: parameters.clone(),
+        body: body.clone(),
+        env: Rc::clone(&env),
+      };
+      if let Some(name) = name {
+        env.borrow_mut().set(name, function.clone());
+      }
+      function
+    },
+    Expression::MacroLiteral { parameters, body } => Object::Macro {
+      parameters: parameters.clone(),
+      body: body.clone(),
+      env: Rc::clone(env),
+    },
+    Expression::Call { function, arguments } => {
+      if let Expression::Identifier(name) = function.as_ref() {
+        if name == "quote" {
+          return quote(arguments, env);
+        }
+      }
+      let function = match function.as_ref() {
+        Expression::Identifier(name) => match lookup_identifier(name, env) {
+          Some(function) => function,
+          None => return undefined_function_error(name, arguments.len(), env),
+        },
+        function => eval_expression(function, env),
+      };
+      if function.is_error() {
+        return function;
+      }
+      match eval_expressions(arguments, env) {
+        Ok(args) => {
+          observer::function_called(&function, &args);
+          apply_function(function, args)
+        },
+        Err(e) => e,
+      }
+    },
+    Expression::Index { left, index } => {
+      let left = eval_expression(left, env);
+      if left.is_error() {
+        return left;
+      }
+      let index = eval_expression(index, env);
+      if index.is_error() {
+        return index;
+      }
+      eval_index_expression(left, index)
+    },
+    Expression::Slice { left, start, end } => {
+      let left = eval_expression(left, env);
+      if left.is_error() {
+        return left;
+      }
+      // a missing bound is passed as null, as the vm does
+      let bound = |b: &Option<Box<Expression>>| {
+        b.as_ref().map_or(Object::Null, |b| eval_expression(b, env))
+      };
+      let start = bound(start);
+      if start.is_error() {
+        return start;
+      }
+      let end = bound(end);
+      if end.is_error() {
+        return end;
+      }
+      eval_slice_expression(left, start, end)
+    },
+  }
+}
+
+// `quote` is a special form rather than a builtin: its argument is returned unevaluated, except
+// for any `unquote(...)` calls inside it, which are evaluated and spliced back in as code.
+fn quote(arguments: &[Expression], env: &Env) -> Object {
+  let expression = match arguments {
+    [expression] => expression.clone(),
+    _ => return Object::Error(format!("wrong number of arguments: want=1, got={}", arguments.len())),
+  };
+  let unquoted = ast::modify(expression, &mut |e| match e {
+    Expression::Call { function, arguments } if is_unquote(&function, &arguments) => {
+      let value = eval_expression(&arguments[0], env);
+      match value {
+        Object::Quote(e) => Ok(e),
+        Object::Error(_) => Err(value),
+        value => {
+          let type_name = value.type_name();
+          value.into_literal().ok_or_else(|| Object::Error(format!("cannot unquote {}", type_name)))
+        },
+      }
+    },
+    e => Ok(e),
+  });
+  match unquoted {
+    Ok(e) => Object::Quote(e),
+    Err(e) => e,
+  }
+}
+
+pub(crate) fn is_unquote(function: &Expression, arguments: &[Expression]) -> bool {
+  matches!(function, Expression::Identifier(name) if name == "unquote") && arguments.len() == 1
+}
+
+pub(crate) fn lookup_identifier(name: &str, env: &Env) -> Option<Object> {
+  env.borrow().get(name).or_else(|| builtins::lookup(name))
+}
+
+// Suggests a similarly named function, from the environment or the builtins, that can be
+// called with the given number of arguments.
+fn undefined_function_error(name: &str, arity: usize, env: &Env) -> Object {
+  let env = env.borrow();
+  let mut candidates = vec!();
+  for candidate in env.names() {
+    match env.get(&candidate) {
+      Some(Object::Function { parameters, .. }) if parameters.len() == arity => {
+        let label = format!("{}({})", candidate, parameters.join(", "));
+        candidates.push((candidate, label));
+      },
+      Some(Object::Builtin { function, .. }) if function.accepts(arity) => {
+        let label = format!("{}(...)", candidate);
+        candidates.push((candidate, label));
+      },
+      _ => {},
+    }
+  }
+  for builtin in builtins::BUILTINS {
+    if builtin.accepts(arity) && env.get(builtin.name).is_none() {
+      candidates.push((String::from(builtin.name), builtin.signature()));
+    }
+  }
+
+  match diagnostics::suggest(name, candidates) {
+    Some(label) => Object::Error(format!("identifier not found: {}; did you mean `{}`?", name, label)),
+    None => Object::Error(format!("identifier not found: {}", name)),
+  }
+}
+
+// Evaluates each expression in order, stopping at the first error.
+fn eval_expressions(expressions: &[Expression], env: &Env) -> Result<Vec<Object>, Object> {
+  let mut results = Vec::with_capacity(expressions.len());
+  for expression in expressions {
+    let result = eval_expression(expression, env);
+    if result.is_error() {
+      return Err(result);
+    }
+    results.push(result);
+  }
+  Ok(results)
+}
+
+fn eval_hash_literal(pairs: &[(Expression, Expression)], env: &Env) -> Object {
+  let mut hash = HashMap::with_capacity(pairs.len());
+  for (key, value) in pairs {
+    let key = eval_expression(key, env);
+    if key.is_error() {
+      return key;
+    }
+    let hash_key = match key.hash_key() {
+      Some(k) => k,
+      None => return Object::Error(format!("unusable as hash key: {}", key.type_name())),
+    };
+    let value = eval_expression(value, env);
+    if value.is_error() {
+      return value;
+    }
+    hash.insert(hash_key, HashPair { key, value });
+  }
+  Object::Hash(hash)
+}
+
+// Out-of-bounds array indexes, including negative ones, evaluate to null.
+pub(crate) fn eval_index_expression(left: Object, index: Object) -> Object {
+  match (left, index) {
+    (Object::Array(elements), Object::Integer(i)) => {
+      if i < 0 {
+        return Object::Null;
+      }
+      elements.into_iter().nth(i as usize).unwrap_or(Object::Null)
+    },
+    (Object::Str(s), Object::Integer(i)) => {
+      if i < 0 {
+        return Object::Null;
+      }
+      s.chars().nth(i as usize).map_or(Object::Null, |c| Object::Str(c.to_string()))
+    },
+    (Object::Hash(pairs), index) => match index.hash_key() {
+      Some(key) => pairs.get(&key).map_or(Object::Null, |pair| pair.value.clone()),
+      None => Object::Error(format!("unusable as hash key: {}", index.type_name())),
+    },
+    (left, _) => Object::Error(format!("index operator not supported: {}", left.type_name())),
+  }
+}
+
+/// Slices a string (by chars) or an array. A null bound means the start or end, and bounds past
+/// either end are moved to it, so slicing never fails on an integer bound.
+pub(crate) fn eval_slice_expression(left: Object, start: Object, end: Object) -> Object {
+  let len = match &left {
+    Object::Str(s) => s.chars().count(),
+    Object::Array(elements) => elements.len(),
+    left => return Object::Error(format!("slice operator not supported: {}", left.type_name())),
+  };
+  let bound = |bound: Object, default: usize| match bound {
+    Object::Null => Ok(default),
+    Object::Integer(i) => Ok(i.clamp(0, len as i64) as usize),
+    other => Err(Object::Error(format!("slice bound must be INTEGER, got {}", other.type_name()))),
+  };
+  let (start, end) = match (bound(start, 0), bound(end, len)) {
+    (Ok(start), Ok(end)) => (start, end.max(start)),
+    (Err(e), _) | (_, Err(e)) => return e,
+  };
+  match left {
+    Object::Str(s) => Object::Str(s.chars().skip(start).take(end - start).collect()),
+    Object::Array(elements) => Object::Array(elements[start..end].to_vec()),
+    _ => unreachable!("only strings and arrays have a length"),
+  }
+}
+
+pub(crate) fn apply_function(function: Object, args: Vec<Object>) -> Object {
+  match function {
+    Object::Function { parameters, body, env } => {
+      if parameters.len() != args.len() {
+        return Object::Error(format!(
+          "wrong number of arguments: want={}, got={}", parameters.len(), args.len()
+        ));
+      }
+      let inner = Environment::new_enclosed(&env);
+      for (name, arg) in parameters.iter().zip(args) {
+        inner.borrow_mut().set(name, arg);
+      }
+      match eval_block_statement(&body, &inner) {
+        Object::ReturnValue(value) => *value,
+        result => result,
+      }
+    },
+    Object::Builtin { function, .. } => function.call(args),
+    other => Object::Error(format!("not a function: {}", other.type_name())),
+  }
+}
+
+pub(crate) fn eval_prefix_expression(operator: PrefixOperator, right: Object) -> Object {
+  match (operator, right) {
+    (PrefixOperator::Bang, right) => Object::Boolean(!is_truthy(&right)),
+    (PrefixOperator::Minus, Object::Integer(i)) => match i.checked_neg() {
+      Some(negated) => Object::Integer(negated),
+      None => Object::Error(format!("integer overflow: -({})", i)),
+    },
+    (PrefixOperator::Minus, Object::Float(x)) => Object::Float(-x),
+    (PrefixOperator::Minus, right) => Object::Error(format!("unknown operator: -{}", right.type_name())),
+  }
+}
+
+pub(crate) fn eval_infix_expression(operator: InfixOperator, left: Object, right: Object) -> Object {
+  // with both operands already evaluated there is nothing left to short-circuit
+  match operator {
+    InfixOperator::And => return Object::Boolean(is_truthy(&left) && is_truthy(&right)),
+    InfixOperator::Or => return Object::Boolean(is_truthy(&left) || is_truthy(&right)),
+    _ => {},
+  }
+  match (left, right) {
+    (Object::Integer(l), Object::Integer(r)) => eval_integer_infix_expression(operator, l, r),
+    (Object::Float(l), Object::Float(r)) => eval_float_infix_expression(operator, l, r),
+    (Object::Integer(l), Object::Float(r)) => eval_float_infix_expression(operator, l as f64, r),
+    (Object::Float(l), Object::Integer(r)) => eval_float_infix_expression(operator, l, r as f64),
+    (Object::Boolean(l), Object::Boolean(r)) => match operator {
+      InfixOperator::Eq => Object::Boolean(l == r),
+      InfixOperator::NotEq => Object::Boolean(l != r),
+      _ => Object::Error(format!("unknown operator: BOOLEAN {} BOOLEAN", operator.symbol())),
+    },
+    (Object::Str(l), Object::Str(r)) => match operator {
+      InfixOperator::Plus => Object::Str(l + &r),
+      InfixOperator::Eq => Object::Boolean(l == r),
+      InfixOperator::NotEq => Object::Boolean(l != r),
+      _ => Object::Error(format!("unknown operator: STRING {} STRING", operator.symbol())),
+    },
+    (left, right) => {
+      if left.type_name() != right.type_name() {
+        Object::Error(format!(
+          "type mismatch: {} {} {}", left.type_name(), operator.symbol(), right.type_name()
+        ))
+      } else {
+        Object::Error(format!(
+          "unknown operator: {} {} {}", left.type_name(), operator.symbol(), right.type_name()
+        ))
+      }
+    },
+  }
+}
+
+fn eval_integer_infix_expression(operator: InfixOperator, l: i64, r: i64) -> Object {
+  let checked = match operator {
+    InfixOperator::Plus => l.checked_add(r),
+    InfixOperator::Minus => l.checked_sub(r),
+    InfixOperator::Asterisk => l.checked_mul(r),
+    InfixOperator::Slash => {
+      if r == 0 {
+        return Object::Error(String::from("division by zero"));
+      }
+      l.checked_div(r)
+    },
+    InfixOperator::TildeSlash => {
+      if r == 0 {
+        return Object::Error(String::from("division by zero"));
+      }
+      l.checked_div(r).map(|q| if l % r != 0 && (l < 0) != (r < 0) { q - 1 } else { q })
+    },
+    InfixOperator::Percent => {
+      if r == 0 {
+        return Object::Error(String::from("division by zero"));
+      }
+      l.checked_rem(r)
+    },
+    InfixOperator::Ampersand => Some(l & r),
+    InfixOperator::Pipe => Some(l | r),
+    InfixOperator::Caret => Some(l ^ r),
+    // bits shifted out are lost rather than reported as overflow, as bitwise code expects
+    InfixOperator::ShiftLeft | InfixOperator::ShiftRight => {
+      if !(0..64).contains(&r) {
+        return Object::Error(format!("shift amount out of range: {} {} {}", l, operator.symbol(), r));
+      }
+      Some(if operator == InfixOperator::ShiftLeft { l << r } else { l >> r })
+    },
+    InfixOperator::GT => return Object::Boolean(l > r),
+    InfixOperator::LT => return Object::Boolean(l < r),
+    InfixOperator::GtEq => return Object::Boolean(l >= r),
+    InfixOperator::LtEq => return Object::Boolean(l <= r),
+    InfixOperator::Eq => return Object::Boolean(l == r),
+    InfixOperator::NotEq => return Object::Boolean(l != r),
+    InfixOperator::And | InfixOperator::Or => unreachable!("logical operators don't depend on type"),
+  };
+  match checked {
+    Some(i) => Object::Integer(i),
+    None => Object::Error(format!("integer overflow: {} {} {}", l, operator.symbol(), r)),
+  }
+}
+
+// Mixed integer and float operands are promoted to floats. Float arithmetic follows IEEE 754,
+// so dividing by zero gives an infinity (or NaN) rather than an error.
+fn eval_float_infix_expression(operator: InfixOperator, l: f64, r: f64) -> Object {
+  match operator {
+    InfixOperator::Plus => Object::Float(l + r),
+    InfixOperator::Minus => Object::Float(l - r),
+    InfixOperator::Asterisk => Object::Float(l * r),
+    InfixOperator::Slash | InfixOperator::TildeSlash => Object::Float(l / r),
+    InfixOperator::Percent => Object::Float(l % r),
+    InfixOperator::Ampersand | InfixOperator::Pipe | InfixOperator::Caret | InfixOperator::ShiftLeft
+      | InfixOperator::ShiftRight =>
+      Object::Error(format!("unknown operator: FLOAT {} FLOAT", operator.symbol())),
+    InfixOperator::GT => Object::Boolean(l > r),
+    InfixOperator::LT => Object::Boolean(l < r),
+    InfixOperator::GtEq => Object::Boolean(l >= r),
+    InfixOperator::LtEq => Object::Boolean(l <= r),
+    InfixOperator::Eq => Object::Boolean(l == r),
+    InfixOperator::NotEq => Object::Boolean(l != r),
+    InfixOperator::And | InfixOperator::Or => unreachable!("logical operators don't depend on type"),
+  }
+}
+
+pub(crate) fn is_truthy(o: &Object) -> bool {
+  !matches!(o, Object::Null | Object::Boolean(false))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn run(input: &str) -> Object {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap_or_else(|e| {
+      panic!("parse errors for {:?}: {}", input, e)
+    });
+    match eval(&program, &Environment::new()) {
+      Ok(value) => value,
+      Err(MonkeyError::Runtime { message, .. }) => Object::Error(message),
+      Err(e) => panic!("evaluating {:?} failed with {}", input, e),
+    }
+  }
+
+  fn error(message: &str) -> Object {
+    Object::Error(String::from(message))
+  }
+
+  #[test]
+  fn test_integer_and_boolean_expressions() {
+    let cases = [
+      ("5", Object::Integer(5)),
+      ("-10", Object::Integer(-10)),
+      ("5 + 5 + 5 + 5 - 10", Object::Integer(10)),
+      ("-50 + 100 + -50", Object::Integer(0)),
+      ("20 + 2 * -10 / 4", Object::Integer(15)),
+      ("(5 + 10 * 2 + 15 / 3) * 2 + -10", Object::Integer(50)),
+      ("1 < 2", Object::Boolean(true)),
+      ("1 > 2 == false", Object::Boolean(true)),
+      ("(1 < 2) != true", Object::Boolean(false)),
+      ("2 <= 2", Object::Boolean(true)),
+      ("3 >= 4", Object::Boolean(false)),
+      ("!5", Object::Boolean(false)),
+      ("!!true", Object::Boolean(true)),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_negative_integers() {
+    let cases = [
+      ("-9223372036854775807", Object::Integer(-i64::MAX)),
+      ("- -5", Object::Integer(5)),
+      ("-5 - -5", Object::Integer(0)),
+      ("-3 * 4", Object::Integer(-12)),
+      ("-7 / 2", Object::Integer(-3)),
+      ("-7 ~/ 2", Object::Integer(-4)),
+      ("7 ~/ -2", Object::Integer(-4)),
+      ("-6 ~/ 3", Object::Integer(-2)),
+      ("7 ~/ 2 * 2", Object::Integer(6)),
+      ("7 ~/ 0", error("division by zero")),
+      ("let min = -9223372036854775807 - 1; min ~/ -1", error(
+        "integer overflow: -9223372036854775808 ~/ -1"
+      )),
+      ("-1 < 0", Object::Boolean(true)),
+      ("let min = -9223372036854775807 - 1; min", Object::Integer(i64::MIN)),
+      ("let min = -9223372036854775807 - 1; -min", error("integer overflow: -(-9223372036854775808)")),
+      ("9223372036854775807 + 1", error("integer overflow: 9223372036854775807 + 1")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_floats() {
+    let cases = [
+      ("3.5", Object::Float(3.5)),
+      ("-1.5", Object::Float(-1.5)),
+      ("1.5 + 2.25", Object::Float(3.75)),
+      ("1 + 0.5", Object::Float(1.5)),
+      ("0.5 * 4", Object::Float(2.0)),
+      ("7 / 2.0", Object::Float(3.5)),
+      ("-7 ~/ 2.0", Object::Float(-3.5)),
+      ("1.0 / 0", Object::Float(f64::INFINITY)),
+      ("1 == 1.0", Object::Boolean(true)),
+      ("2.5 > 2", Object::Boolean(true)),
+      ("2.5 >= 2.5", Object::Boolean(true)),
+      ("0.0 / 0 <= 1", Object::Boolean(false)),
+      ("1e-9 < 0", Object::Boolean(false)),
+      ("0.1 + 0.2 != 0.3", Object::Boolean(true)),
+      ("1.5 + true", error("type mismatch: FLOAT + BOOLEAN")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+    assert_eq!(run("2.0 * 3").to_string(), "6.0");
+  }
+
+  #[test]
+  fn test_if_else_expressions() {
+    assert_eq!(run("if (true) { 10 }"), Object::Integer(10));
+    assert_eq!(run("if (false) { 10 }"), Object::Null);
+    assert_eq!(run("if (1 > 2) { 10 } else { 20 }"), Object::Integer(20));
+  }
+
+  #[test]
+  fn test_modulo_and_bitwise_operators() {
+    let cases = [
+      ("7 % 3", Object::Integer(1)),
+      ("-7 % 3", Object::Integer(-1)),
+      ("7 % -3", Object::Integer(1)),
+      ("15 % 5 == 0", Object::Boolean(true)),
+      ("7.5 % 2", Object::Float(1.5)),
+      ("6 & 3", Object::Integer(2)),
+      ("6 | 3", Object::Integer(7)),
+      ("6 ^ 3", Object::Integer(5)),
+      ("1 << 4", Object::Integer(16)),
+      ("-16 >> 2", Object::Integer(-4)),
+      ("1 << 63", Object::Integer(i64::MIN)),
+      ("1 % 0", error("division by zero")),
+      ("(-9223372036854775807 - 1) % -1", error("integer overflow: -9223372036854775808 % -1")),
+      ("1 << 64", error("shift amount out of range: 1 << 64")),
+      ("8 >> -1", error("shift amount out of range: 8 >> -1")),
+      ("1.0 & 1", error("unknown operator: FLOAT & FLOAT")),
+      ("true | false", error("unknown operator: BOOLEAN | BOOLEAN")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_logical_operators() {
+    let cases = [
+      ("true && false", Object::Boolean(false)),
+      ("1 && \"\"", Object::Boolean(true)),
+      ("false || 0", Object::Boolean(true)),
+      ("if (false) { 1 } || false", Object::Boolean(false)),
+      ("1 < 2 && 2 < 3 || false", Object::Boolean(true)),
+      // the right operand only runs when it decides the result
+      ("false && -true", Object::Boolean(false)),
+      ("true || undefined", Object::Boolean(true)),
+      ("let n = 0; let f = fn() { n = n + 1 }; true && f(); false || f(); n", Object::Integer(2)),
+      ("true && -true", error("unknown operator: -BOOLEAN")),
+      ("-true || true", error("unknown operator: -BOOLEAN")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_assignment() {
+    let cases = [
+      ("let x = 1; x = x + 1; x", Object::Integer(2)),
+      ("let x = 1; let f = fn() { x = 5 }; f(); x", Object::Integer(5)),
+      // a parameter shadows the outer binding, so only it changes
+      ("let x = 1; let f = fn(x) { x = 5; x }; f(0) + x", Object::Integer(6)),
+      ("let count = fn() { let n = 0; fn() { n = n + 1; n } }; let c = count(); c(); c()", Object::Integer(2)),
+      ("y = 1", error("cannot assign to undeclared identifier: y")),
+      ("len = 1", error("cannot assign to undeclared identifier: len")),
+      ("let f = fn() { let z = 1; }; f(); z = 2", error("cannot assign to undeclared identifier: z")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_while_expressions() {
+    let sum = "let i = 0; let sum = 0; while (i < 5) { i = i + 1; sum = sum + i; } sum";
+    assert_eq!(run(sum), Object::Integer(15));
+    assert_eq!(run("while (false) { 1 }"), Object::Null);
+    assert_eq!(run("let f = fn() { while (true) { return 5; } }; f()"), Object::Integer(5));
+    assert_eq!(run("while (1 + true) { 1 }"), error("type mismatch: INTEGER + BOOLEAN"));
+    assert_eq!(run("while (true) { -true }"), error("unknown operator: -BOOLEAN"));
+  }
+
+  #[test]
+  fn test_return_statements() {
+    assert_eq!(run("9; return 2 * 5; 9;"), Object::Integer(10));
+    assert_eq!(run("
+      if (10 > 1) {
+        if (10 > 1) {
+          return 10;
+        }
+        return 1;
+      }
+    "), Object::Integer(10));
+  }
+
+  #[test]
+  fn test_error_handling() {
+    let cases = [
+      ("5 + true; 5;", "type mismatch: INTEGER + BOOLEAN"),
+      ("-true", "unknown operator: -BOOLEAN"),
+      ("if (10 > 1) { true + false; }", "unknown operator: BOOLEAN + BOOLEAN"),
+      ("foobar", "identifier not found: foobar"),
+      ("5 / 0", "division by zero"),
+      ("5(1)", "not a function: INTEGER"),
+      ("fn(x) { x }()", "wrong number of arguments: want=1, got=0"),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(run(input), error(expected), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_closures() {
+    let cases = [
+      ("let new_adder = fn(x) { fn(y) { x + y } }; let add_two = new_adder(2); add_two(3)", 5),
+      ("let add = fn(a) { fn(b) { fn(c) { a + b + c } } }; add(1)(2)(3)", 6),
+      // each call gets its own environment, so earlier closures keep their values
+      ("
+        let new_adder = fn(x) { fn(y) { x + y } };
+        let add_one = new_adder(1);
+        let add_ten = new_adder(10);
+        add_one(0) * 100 + add_ten(0)
+      ", 110),
+      // a counter that hands back its successor alongside its count
+      ("
+        let counter = fn(n) { [n, fn() { counter(n + 1) }] };
+        let c = counter(0);
+        let c = c[1]();
+        let c = c[1]();
+        c[0]
+      ", 2),
+      // closures see bindings made in their defining scope after they were created
+      ("let f = fn() { later }; let later = 7; f()", 7),
+      ("let x = 1; let f = fn(x) { fn() { x } }; f(5)()", 5),
+      // a named literal calls itself, even once the name it was bound to means something else
+      ("fn fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }(5)", 120),
+      ("let f = fn count(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; let count = 0; f(3)", 3),
+      ("let g = fn f(n) { if (n == 0) { 0 } else { f(n - 1) } }; let f = fn(n) { 99 }; g(2)", 0),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(run(input), Object::Integer(*expected), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_undefined_function_suggestions() {
+    let cases = [
+      ("pussh([1], 2)", "identifier not found: pussh; did you mean `push(arr, x)`?"),
+      ("frist([1])", "identifier not found: frist; did you mean `first(arr)`?"),
+      ("let add = fn(a, b) { a + b }; ad(1, 2)", "identifier not found: ad; did you mean `add(a, b)`?"),
+      // arity rules out `add`
+      ("let add = fn(a, b) { a + b }; ad(1)", "identifier not found: ad"),
+      ("let add = 5; ad(1, 2)", "identifier not found: ad"),
+      ("completely_unknown(1)", "identifier not found: completely_unknown"),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(run(input), error(expected), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_registered_function_suggestions() {
+    let env = Environment::new();
+    env.borrow_mut().register_fn("square", |x: i64| Ok(x * x));
+    env.borrow_mut().register_fn("count", |args: &[Object]| Ok(Object::Integer(args.len() as i64)));
+    let cases = [
+      ("sqare(2)", "identifier not found: sqare; did you mean `square(...)`?"),
+      // `square` takes one argument, while `count` takes any number
+      ("sqare(2, 3)", "identifier not found: sqare"),
+      ("cont(1, 2, 3)", "identifier not found: cont; did you mean `count(...)`?"),
+    ];
+    for (input, expected) in cases.iter() {
+      let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+      let message = String::from(*expected);
+      assert_eq!(eval(&program, &env), Err(MonkeyError::runtime(message)), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_strings() {
+    assert_eq!(run(r#""Hello" + " " + "World!""#), Object::Str(String::from("Hello World!")));
+    assert_eq!(run(r#""a" == "a""#), Object::Boolean(true));
+    assert_eq!(run(r#""a" != "a""#), Object::Boolean(false));
+    assert_eq!(run(r#""a" - "b""#), error("unknown operator: STRING - STRING"));
+    assert_eq!(run(r#""a" + 1"#), error("type mismatch: STRING + INTEGER"));
+  }
+
+  #[test]
+  fn test_indexing_and_slicing_strings() {
+    let cases = [
+      (r#""hello"[1]"#, Object::Str(String::from("e"))),
+      (r#""héllo"[1]"#, Object::Str(String::from("é"))),
+      (r#""hello"[5]"#, Object::Null),
+      (r#""hello"[-1]"#, Object::Null),
+      (r#""hello"[1:3]"#, Object::Str(String::from("el"))),
+      (r#""hello"[:2] + "hello"[3:]"#, Object::Str(String::from("helo"))),
+      (r#""hello"[:]"#, Object::Str(String::from("hello"))),
+      (r#""hello"[-5:100]"#, Object::Str(String::from("hello"))),
+      (r#""hello"[4:2]"#, Object::Str(String::new())),
+      ("[1, 2, 3, 4][1:3]", Object::Array(vec!(Object::Integer(2), Object::Integer(3)))),
+      ("[1, 2][2:]", Object::Array(vec!())),
+      (r#""hello"["a":]"#, error("slice bound must be INTEGER, got STRING")),
+      (r#"{}[1:2]"#, error("slice operator not supported: HASH")),
+      (r#""hello"[:-true]"#, error("unknown operator: -BOOLEAN")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_arrays() {
+    assert_eq!(run("[1, 2 * 2, 3 + 3]"), Object::Array(vec!(
+      Object::Integer(1), Object::Integer(4), Object::Integer(6),
+    )));
+    let cases = [
+      ("[1, 2, 3][0]", Object::Integer(1)),
+      ("[1, 2, 3][1 + 1]", Object::Integer(3)),
+      ("let i = 0; [1][i];", Object::Integer(1)),
+      ("let a = [1, 2, 3]; a[0] + a[1] + a[2];", Object::Integer(6)),
+      ("[1, 2, 3][3]", Object::Null),
+      ("[1, 2, 3][-1]", Object::Null),
+      ("1[0]", error("index operator not supported: INTEGER")),
+      ("[1, foo]", error("identifier not found: foo")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_hashes() {
+    let h = run(r#"
+      let two = "two";
+      {"one": 10 - 9, two: 1 + 1, "thr" + "ee": 6 / 2, 4: 4, true: 5, false: 6}
+    "#);
+    assert_eq!(h.to_string(), "{4: 4, false: 6, true: 5, one: 1, three: 3, two: 2}");
+
+    let cases = [
+      (r#"{"foo": 5}["foo"]"#, Object::Integer(5)),
+      (r#"{"foo": 5}["bar"]"#, Object::Null),
+      (r#"let key = "foo"; {"foo": 5}[key]"#, Object::Integer(5)),
+      (r#"{}["foo"]"#, Object::Null),
+      ("{5: 5}[5]", Object::Integer(5)),
+      ("{true: 5}[true]", Object::Integer(5)),
+      (r#"{"name": "Monkey"}[fn(x) { x }]"#, error("unusable as hash key: FUNCTION")),
+      ("{[1]: 2}", error("unusable as hash key: ARRAY")),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(&run(input), expected, "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_quote_unquote() {
+    let cases = [
+      ("quote(5)", "5"),
+      ("quote(foobar + barfoo)", "(foobar + barfoo)"),
+      ("quote(unquote(4 + 4) + 8)", "(8 + 8)"),
+      ("let x = 8; quote(unquote(x) + unquote(1.5 > 1))", "(8 + true)"),
+      ("let q = quote(4 + 4); quote(unquote(q) * unquote(q))", "((4 + 4) * (4 + 4))"),
+      (r#"quote(unquote("a" + "b"))"#, r#""ab""#),
+    ];
+    for (input, expected) in cases.iter() {
+      match run(input) {
+        Object::Quote(e) => assert_eq!(e.to_string(), *expected, "input {:?}", input),
+        other => panic!("input {:?} gave {}, not a quote", input, other),
+      }
+    }
+    assert_eq!(run("quote(1, 2)"), error("wrong number of arguments: want=1, got=2"));
+    assert_eq!(run("quote(unquote([1]))"), error("cannot unquote ARRAY"));
+    assert_eq!(run("quote(unquote(-true))"), error("unknown operator: -BOOLEAN"));
+  }
+
+  #[test]
+  fn test_let_statements_and_functions() {
+    assert_eq!(run("let a = 5 * 5; let b = a; b + a;"), Object::Integer(50));
+    assert_eq!(run("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));"), Object::Integer(20));
+    assert_eq!(run("fn(x) { x; }(5)"), Object::Integer(5));
+    assert_eq!(run("
+      let fact = fn(n) { if (n < 2) { return 1; } n * fact(n - 1) };
+      fact(5);
+    "), Object::Integer(120));
+  }
+}
@@ -0,0 +1,334 @@
+use crate::ast::{self, *};
+use crate::parser::{infix_precedence, Precedence};
+
+// Blocks holding a single short expression stay on one line, as in `fn(x) { x * 2 }`.
+const INLINE_BLOCK_WIDTH: usize = 40;
+
+/// Prints a program as canonical Monkey source: one statement per line, blocks indented by two
+/// spaces, and parentheses only where precedence needs them. Parsing the output gives back the
+/// same program. Comments are not part of the AST, so they are lost.
+pub fn format_program(program: &Program) -> String {
+  let mut printer = Printer { out: String::new(), indent: 0 };
+  printer.statements(&program.statements, true);
+  if !program.statements.is_empty() {
+    printer.out.push('\n');
+  }
+  printer.out
+}
+
+struct Printer {
+  out: String,
+  indent: usize,
+}
+
+impl Printer {
+  fn render<F: FnOnce(&mut Printer)>(&self, f: F) -> String {
+    let mut printer = Printer { out: String::new(), indent: self.indent };
+    f(&mut printer);
+    printer.out
+  }
+
+  fn newline(&mut self) {
+    self.out.push('\n');
+    self.out.push_str(&"  ".repeat(self.indent));
+  }
+
+  // The last statement of a block is its value, so only it goes without a semicolon.
+  fn statements(&mut self, statements: &[Statement], top_level: bool) {
+    let rendered: Vec<String> = statements.iter().map(|s| self.render(|p| p.statement(s))).collect();
+    for (i, text) in rendered.iter().enumerate() {
+      if i > 0 {
+        // separate multi-line definitions at the top level with a blank line
+        if top_level && (text.contains('\n') || rendered[i - 1].contains('\n')) {
+          self.out.push('\n');
+        }
+        self.newline();
+      }
+      self.out.push_str(text);
+      let next = rendered.get(i + 1);
+      let semicolon = match &statements[i] {
+        // `if (x) { y }` followed by `(z)` on the next line would parse as a call
+        Statement::Expression(e) if ends_with_block(e) =>
+          next.is_some_and(|n| n.starts_with(['(', '[', '-'])),
+        Statement::Expression(_) => top_level || next.is_some(),
+        _ => true,
+      };
+      if semicolon {
+        self.out.push(';');
+      }
+    }
+  }
+
+  fn statement(&mut self, statement: &Statement) {
+    match statement {
+      Statement::Let(name, value) => {
+        self.out.push_str(&format!("let {} = ", name));
+        self.expression(value);
+      },
+      Statement::Assign(name, value) => {
+        self.out.push_str(&format!("{} = ", name));
+        self.expression(value);
+      },
+      Statement::Return(value) => {
+        self.out.push_str("return ");
+        self.expression(value);
+      },
+      Statement::Expression(e) => self.expression(e),
+    }
+  }
+
+  fn inline_block(&self, block: &BlockStatement) -> Option<String> {
+    match block.statements.as_slice() {
+      [] => Some(String::from("{}")),
+      [Statement::Expression(e)] => {
+        let text = self.render(|p| p.expression(e));
+        if text.contains('\n') || text.len() > INLINE_BLOCK_WIDTH {
+          None
+        } else {
+          Some(format!("{{ {} }}", text))
+        }
+      },
+      _ => None,
+    }
+  }
+
+  fn block(&mut self, block: &BlockStatement, inline: bool) {
+    if let Some(text) = self.inline_block(block).filter(|_| inline) {
+      return self.out.push_str(&text);
+    }
+    self.out.push('{');
+    self.indent += 1;
+    self.newline();
+    self.statements(&block.statements, false);
+    self.indent -= 1;
+    self.newline();
+    self.out.push('}');
+  }
+
+  fn expression(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Identifier(name) => self.out.push_str(name),
+      Expression::IntegerLiteral(i) => self.out.push_str(&i.to_string()),
+      Expression::FloatLiteral(x) => self.out.push_str(&format!("{:?}", x)),
+      Expression::StringLiteral(s) => self.out.push_str(&format!("\"{}\"", ast::escape(s))),
+      Expression::Boolean(b) => self.out.push_str(&b.to_string()),
+      Expression::ArrayLiteral(elements) => {
+        self.out.push('[');
+        self.list(elements);
+        self.out.push(']');
+      },
+      Expression::HashLiteral(pairs) => {
+        self.out.push('{');
+        for (i, (key, value)) in pairs.iter().enumerate() {
+          if i > 0 {
+            self.out.push_str(", ");
+          }
+          self.expression(key);
+          self.out.push_str(": ");
+          self.expression(value);
+        }
+        self.out.push('}');
+      },
+      Expression::Prefix(operator, right) => {
+        self.out.push_str(operator.symbol());
+        self.operand(right, precedence(right) < Precedence::Prefix);
+      },
+      Expression::Infix(operator, left, right) => {
+        // operators are left-associative, so a right operand of equal precedence needs parentheses
+        let p = infix_precedence(*operator);
+        self.operand(left, precedence(left) < p);
+        self.out.push_str(&format!(" {} ", operator.symbol()));
+        self.operand(right, precedence(right) <= p);
+      },
+      Expression::If { condition, consequence, alternative } => {
+        // both branches go on one line or neither does
+        let inline = self.inline_block(consequence).is_some()
+          && alternative.iter().all(|a| self.inline_block(a).is_some());
+        self.out.push_str("if (");
+        self.expression(condition);
+        self.out.push_str(") ");
+        self.block(consequence, inline);
+        if let Some(alternative) = alternative {
+          self.out.push_str(" else ");
+          self.block(alternative, inline);
+        }
+      },
+      Expression::While { condition, body } => {
+        self.out.push_str("while (");
+        self.expression(condition);
+        self.out.push_str(") ");
+        self.block(body, true);
+      },
+      Expression::FunctionLiteral { name, parameters, body } => {
+        self.out.push_str("fn");
+        if let Some(name) = name {
+          self.out.push_str(&format!(" {}", name));
+        }
+        self.out.push_str(&format!("({}) ", parameters.join(", ")));
+        self.block(body, true);
+      },
+      Expression::MacroLiteral { parameters, body } => {
+        self.out.push_str(&format!("macro({}) ", parameters.join(", ")));
+        self.block(body, true);
+      },
+      Expression::Call { function, arguments } => {
+        self.operand(function, precedence(function) < Precedence::Call);
+        self.out.push('(');
+        self.list(arguments);
+        self.out.push(')');
+      },
+      Expression::Index { left, index } => {
+        self.operand(left, precedence(left) < Precedence::Call);
+        self.out.push('[');
+        self.expression(index);
+        self.out.push(']');
+      },
+      Expression::Slice { left, start, end } => {
+        self.operand(left, precedence(left) < Precedence::Call);
+        self.out.push('[');
+        if let Some(start) = start {
+          self.expression(start);
+        }
+        self.out.push(':');
+        if let Some(end) = end {
+          self.expression(end);
+        }
+        self.out.push(']');
+      },
+    }
+  }
+
+  fn operand(&mut self, expression: &Expression, parenthesize: bool) {
+    if parenthesize {
+      self.out.push('(');
+    }
+    self.expression(expression);
+    if parenthesize {
+      self.out.push(')');
+    }
+  }
+
+  fn list(&mut self, expressions: &[Expression]) {
+    for (i, e) in expressions.iter().enumerate() {
+      if i > 0 {
+        self.out.push_str(", ");
+      }
+      self.expression(e);
+    }
+  }
+}
+
+// How tightly an expression holds together when printed without parentheses. A negative
+// literal prints with a leading `-`, so it binds like a prefix expression.
+fn precedence(expression: &Expression) -> Precedence {
+  match expression {
+    Expression::Infix(operator, _, _) => infix_precedence(*operator),
+    Expression::Prefix(_, _) => Precedence::Prefix,
+    Expression::IntegerLiteral(i) if *i < 0 => Precedence::Prefix,
+    Expression::FloatLiteral(x) if x.is_sign_negative() => Precedence::Prefix,
+    _ => Precedence::Index,
+  }
+}
+
+fn ends_with_block(expression: &Expression) -> bool {
+  matches!(expression, Expression::If { .. } | Expression::While { .. }
+    | Expression::FunctionLiteral { .. } | Expression::MacroLiteral { .. })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap_or_else(|e| panic!("{} in {}", e, input))
+  }
+
+  fn format(input: &str) -> String {
+    format_program(&parse(input))
+  }
+
+  #[test]
+  fn test_format_program() {
+    let tests = vec!(
+      ("let x=1+2*3;x", "let x = 1 + 2 * 3;\nx;\n"),
+      ("(1 + 2) * 3; 1 - (2 - 3); (1 - 2) - 3;", "(1 + 2) * 3;\n1 - (2 - 3);\n1 - 2 - 3;\n"),
+      ("-(a + b); !-x; (-f)(x); -f(x); (a + b)[0]; a[1:]", "-(a + b);\n!-x;\n(-f)(x);\n-f(x);\n(a + b)[0];\na[1:];\n"),
+      ("a || b && c; (a || b) && c", "a || b && c;\n(a || b) && c;\n"),
+      (r#"{"a\n": [1, 2.5], true: f(x, y)}"#, "{\"a\\n\": [1, 2.5], true: f(x, y)};\n"),
+      ("let inc = fn(x) { x + 1 }; let id = fn() {}", "let inc = fn(x) { x + 1 };\nlet id = fn() {};\n"),
+      ("let f = fn(x) { let y = x; return y; };", "\
+let f = fn(x) {
+  let y = x;
+  return y;
+};
+"),
+      ("while (i < 3) { puts(i); i = i + 1; }; if (x) { 1 } else { 2 }", "\
+while (i < 3) {
+  puts(i);
+  i = i + 1;
+}
+
+if (x) { 1 } else { 2 }
+"),
+      ("if (x) { 1 } else { let y = 2; y }", "\
+if (x) {
+  1
+} else {
+  let y = 2;
+  y
+}
+"),
+    );
+    for (input, expected) in tests {
+      assert_eq!(format(input), expected, "formatting {}", input);
+    }
+  }
+
+  #[test]
+  fn test_block_statements_keep_their_meaning() {
+    // without the semicolon, the if would be indexed with 2
+    assert_eq!(format("if (x) { 1 }; [2]"), "if (x) { 1 };\n[2];\n");
+    assert_eq!(format("let f = fn() { if (x) { 1 }; -2 }"), "\
+let f = fn() {
+  if (x) { 1 };
+  -2
+};
+");
+    assert_eq!(format("fn() { while (x) { y } puts(1) }"), "\
+fn() {
+  while (x) { y }
+  puts(1)
+}
+");
+  }
+
+  #[test]
+  fn test_format_round_trips() {
+    let sources = vec!(
+      include_str!("../examples/fibonacci.monkey"),
+      include_str!("../examples/fizzbuzz.monkey"),
+      include_str!("../examples/higher_order.monkey"),
+      include_str!("../examples/unless.monkey"),
+      "1 << 2 >> 3 | 4 ^ 5 & 6 % 7; a >= b != (c <= d); x == (y == z)",
+      "let s = \"a\\\"b\\\\\"; s[:2]; s[1:len(s)]; [1, 2][0](3)",
+      "let m = macro(a) { quote(unquote(a) + 1) }; if (if (a) { b }) { c }",
+      "let f = fn loop(n) { if (n > 0) { loop(n - 1) } }; fn named() { 1 }; [2]",
+    );
+    for source in sources {
+      let program = parse(source);
+      let formatted = format_program(&program);
+      assert_eq!(parse(&formatted), program, "round trip of\n{}", formatted);
+      assert_eq!(format(&formatted), formatted);
+    }
+  }
+
+  #[test]
+  fn test_format_folded_literals() {
+    // negative literals don't come from the parser, but do from constant folding
+    let mut program = parse("(0 - 2)[0]; x - (0 - 2)");
+    crate::optimize::fold_constants(&mut program);
+    assert_eq!(format_program(&program), "(-2)[0];\nx - -2;\n");
+  }
+}
@@ -0,0 +1,375 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+use crate::error::MonkeyError;
+use crate::lexer::Lexer;
+use crate::native::NativeFn;
+use crate::object::Object;
+use crate::observer::SharedObserver;
+use crate::parser::Parser;
+use crate::repl;
+use crate::session::{Checkpoint, Engine, Session};
+
+/// Runs Monkey as a scripting language inside another Rust program. Globals, functions and
+/// macros defined by one call to `eval_str` stay defined for the next.
+///
+/// Values cross over as `Object`s, which convert from Rust primitives, strings, vectors and
+/// options with `From`, and back with `TryFrom`:
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use monkey_interpreter::interpreter::Interpreter;
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.set_global("limit", 3);
+/// interpreter.eval_str("let below = fn(x) { x < limit };").unwrap();
+/// let result = interpreter.call_function("below", vec!(2.into())).unwrap();
+/// assert_eq!(bool::try_from(result), Ok(true));
+/// ```
+pub struct Interpreter {
+  session: Session,
+  observer: Option<SharedObserver>,
+}
+
+impl Interpreter {
+  /// An interpreter using the tree-walking evaluator.
+  pub fn new() -> Self {
+    Interpreter::with_engine(Engine::Eval)
+  }
+
+  pub fn with_engine(engine: Engine) -> Self {
+    Interpreter { session: Session::new(engine), observer: None }
+  }
+
+  /// Tells `observer` as each script starts, and of the statements, calls and output of the
+  /// scripts and functions run from then on. Output from `puts` goes to the observer instead of
+  /// stdout.
+  pub fn set_observer(&mut self, observer: SharedObserver) {
+    self.session.set_observer(Rc::clone(&observer));
+    self.observer = Some(observer);
+  }
+
+  /// Passes text showing values through `redactor` wherever the interpreter shows it: in what
+  /// `puts` prints, in error messages, and in the values `repl` echoes.
+  pub fn set_redactor<F: Fn(&str) -> String + 'static>(&mut self, redactor: F) {
+    self.session.set_redactor(Rc::new(redactor));
+  }
+
+  /// Runs a REPL on the interpreter's globals; see `repl::start`.
+  pub fn repl<R: BufRead, W: Write>(&mut self, input: R, output: W) -> io::Result<()> {
+    repl::run(input, output, &mut self.session)
+  }
+
+  /// Runs source code, returning the value of its last statement. A runtime error records where
+  /// the statement it stopped in starts.
+  pub fn eval_str(&mut self, source: &str) -> Result<Object, MonkeyError> {
+    if let Some(mut observer) = self.observer.as_ref().and_then(|o| o.try_borrow_mut().ok()) {
+      observer.script_started(source);
+    }
+    let mut parser = Parser::new(Lexer::new(source));
+    let program = parser.parse_program()?;
+    self.session.run_at(program, parser.statement_spans())
+  }
+
+  pub fn set_global<V: Into<Object>>(&mut self, name: &str, value: V) {
+    self.session.set_global(name, value.into());
+  }
+
+  pub fn get_global(&mut self, name: &str) -> Option<Object> {
+    self.session.get_global(name)
+  }
+
+  /// Makes a Rust function or closure callable from scripts; see `NativeFn` for the forms it
+  /// can take.
+  pub fn register_fn<Args, F: NativeFn<Args>>(&mut self, name: &str, function: F) {
+    self.session.set_global(name, Object::native(name, function));
+  }
+
+  /// Calls a function that scripts have defined, or a builtin.
+  pub fn call_function(&mut self, name: &str, args: Vec<Object>) -> Result<Object, MonkeyError> {
+    self.session.call_function(name, args)
+  }
+}
+
+impl Default for Interpreter {
+  fn default() -> Self {
+    Interpreter::new()
+  }
+}
+
+/// Interpreters made ahead of time, so that a server running a script per request doesn't pay
+/// for registering functions and running a prelude on each one. Each goes back to the state it
+/// was made in when the request is done with it.
+///
+/// ```
+/// use monkey_interpreter::interpreter::{Interpreter, InterpreterPool};
+///
+/// let pool = InterpreterPool::new(2, || {
+///   let mut interpreter = Interpreter::new();
+///   interpreter.register_fn("shout", |s: String| Ok(s.to_uppercase()));
+///   interpreter.eval_str("let greet = fn(name) { shout(\"hi \" + name) };")?;
+///   Ok(interpreter)
+/// }).unwrap();
+///
+/// let mut interpreter = pool.get().unwrap();
+/// assert_eq!(interpreter.eval_str("let me = greet(\"ann\"); me").unwrap().to_string(), "HI ANN");
+/// drop(interpreter);
+/// assert_eq!(pool.get().unwrap().get_global("me"), None);
+/// ```
+pub struct InterpreterPool {
+  make: Box<dyn Fn() -> Result<Interpreter, MonkeyError>>,
+  capacity: usize,
+  idle: RefCell<Vec<(Interpreter, Checkpoint)>>,
+}
+
+impl InterpreterPool {
+  /// A pool that keeps up to `capacity` idle interpreters, each as `make` leaves it, and starts
+  /// with that many.
+  pub fn new<F>(capacity: usize, make: F) -> Result<Self, MonkeyError>
+  where F: Fn() -> Result<Interpreter, MonkeyError> + 'static {
+    let pool = InterpreterPool { make: Box::new(make), capacity, idle: RefCell::new(vec!()) };
+    for _ in 0..capacity {
+      let made = pool.make()?;
+      pool.idle.borrow_mut().push(made);
+    }
+    Ok(pool)
+  }
+
+  fn make(&self) -> Result<(Interpreter, Checkpoint), MonkeyError> {
+    let interpreter = (self.make)()?;
+    let checkpoint = interpreter.session.checkpoint();
+    Ok((interpreter, checkpoint))
+  }
+
+  /// An idle interpreter, or a new one if none are. It is reset and returned to the pool when
+  /// dropped, unless the pool already has as many idle as it keeps.
+  pub fn get(&self) -> Result<PooledInterpreter<'_>, MonkeyError> {
+    let idle = self.idle.borrow_mut().pop();
+    let entry = match idle {
+      Some(entry) => entry,
+      None => self.make()?,
+    };
+    Ok(PooledInterpreter { pool: self, entry: Some(entry) })
+  }
+
+  /// How many interpreters are waiting to be handed out.
+  pub fn idle(&self) -> usize {
+    self.idle.borrow().len()
+  }
+}
+
+/// An interpreter on loan from an `InterpreterPool`.
+pub struct PooledInterpreter<'a> {
+  pool: &'a InterpreterPool,
+  // only empty once dropped
+  entry: Option<(Interpreter, Checkpoint)>,
+}
+
+impl Deref for PooledInterpreter<'_> {
+  type Target = Interpreter;
+
+  fn deref(&self) -> &Interpreter {
+    &self.entry.as_ref().expect("pooled interpreter already returned").0
+  }
+}
+
+impl DerefMut for PooledInterpreter<'_> {
+  fn deref_mut(&mut self) -> &mut Interpreter {
+    &mut self.entry.as_mut().expect("pooled interpreter already returned").0
+  }
+}
+
+impl Drop for PooledInterpreter<'_> {
+  fn drop(&mut self) {
+    let (mut interpreter, checkpoint) = match self.entry.take() {
+      Some(entry) => entry,
+      None => return,
+    };
+    let mut idle = self.pool.idle.borrow_mut();
+    if idle.len() < self.pool.capacity {
+      interpreter.session.restore(&checkpoint);
+      idle.push((interpreter, checkpoint));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::convert::TryFrom;
+  use crate::lexer::Span;
+  use crate::observer::Observer;
+
+  #[derive(Default)]
+  struct Recorder(Vec<String>);
+
+  impl Observer for Recorder {
+    fn script_started(&mut self, source: &str) {
+      self.0.push(format!("script {}", source.lines().count()));
+    }
+
+    fn statement(&mut self, span: Span) {
+      self.0.push(format!("statement {}:{}", span.line, span.column));
+    }
+
+    fn function_called(&mut self, _function: &Object, args: &[Object]) {
+      let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+      self.0.push(format!("call {}", args.join(", ")));
+    }
+
+    fn output(&mut self, text: &str) {
+      self.0.push(format!("output {}", text));
+    }
+  }
+
+  #[test]
+  fn test_interpreter() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let mut interpreter = Interpreter::with_engine(*engine);
+      interpreter.set_global("greeting", "hello");
+      interpreter.set_global("counts", vec!(1, 2, 3));
+      interpreter.eval_str("let greet = fn(name) { greeting + \", \" + name };").unwrap();
+      let total = "let total = fn(xs) { if (len(xs) == 0) { 0 } else { first(xs) + total(rest(xs)) } };";
+      interpreter.eval_str(total).unwrap();
+
+      let greeted = interpreter.call_function("greet", vec!("monkey".into())).unwrap();
+      assert_eq!(String::try_from(greeted), Ok(String::from("hello, monkey")));
+      let counts = interpreter.get_global("counts").unwrap();
+      assert_eq!(i64::try_from(interpreter.call_function("total", vec!(counts)).unwrap()), Ok(6));
+      assert_eq!(interpreter.call_function("len", vec!("four".into())), Ok(Object::Integer(4)));
+      assert_eq!(interpreter.eval_str("1;\ngreet(1)"), Err(MonkeyError::Runtime {
+        message: String::from("type mismatch: STRING + INTEGER"),
+        span: Some(Span { line: 2, column: 1, byte_offset: 3 }),
+      }));
+      assert_eq!(interpreter.get_global("missing"), None);
+      let missing = interpreter.call_function("missing", vec!());
+      assert_eq!(missing, Err(MonkeyError::runtime(String::from("identifier not found: missing"))));
+    }
+  }
+
+  #[test]
+  fn test_register_fn() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let mut interpreter = Interpreter::with_engine(*engine);
+      let greeting = String::from("hello");
+      interpreter.register_fn("greet", move |name: String| Ok(format!("{}, {}", greeting, name)));
+      interpreter.register_fn("sum", |args: &[Object]| {
+        let mut total = 0;
+        for arg in args {
+          total += i64::try_from(arg.clone())?;
+        }
+        Ok(Object::Integer(total))
+      });
+      interpreter.register_fn("clamp", |x: i64, low: i64, high: i64| Ok(x.max(low).min(high)));
+      interpreter.register_fn("fail", || -> Result<(), MonkeyError> {
+        Err(MonkeyError::runtime(String::from("no disk")))
+      });
+
+      let eval = |interpreter: &mut Interpreter, source: &str| interpreter.eval_str(source);
+      assert_eq!(eval(&mut interpreter, r#"greet("monkey")"#), Ok(Object::from("hello, monkey")));
+      let results = eval(&mut interpreter, "[sum(), sum(1, 2, 3), clamp(12, 0, 10)]");
+      assert_eq!(results, Ok(Object::from(vec!(0, 6, 10))));
+      let start = Span { line: 1, column: 1, byte_offset: 0 };
+      let error = |message: &str| {
+        Err(MonkeyError::Runtime { message: String::from(message), span: Some(start) })
+      };
+      let message = "argument 1 to `greet`: expected STRING, got INTEGER";
+      assert_eq!(eval(&mut interpreter, "greet(1)"), error(message));
+      assert_eq!(eval(&mut interpreter, "clamp(1)"), error("wrong number of arguments. got=1, want=3"));
+      assert_eq!(eval(&mut interpreter, "sum(1, true)"), error("expected INTEGER, got BOOLEAN"));
+      assert_eq!(eval(&mut interpreter, "fail()"), error("no disk"));
+    }
+  }
+
+  #[test]
+  fn test_observer() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let mut interpreter = Interpreter::with_engine(*engine);
+      let recorder = Rc::new(RefCell::new(Recorder::default()));
+      interpreter.set_observer(recorder.clone());
+      interpreter.eval_str("let double = fn(x) { x * 2 };\n  puts(double(2), \"done\");").unwrap();
+      interpreter.call_function("puts", vec!("called".into())).unwrap();
+      assert_eq!(recorder.borrow().0, vec!(
+        "script 2", "statement 1:1", "statement 2:3", "call 2", "call 4, done", "output 4",
+        "output done", "call called",
+        "output called",
+      ), "engine {:?}", engine);
+    }
+  }
+
+  #[test]
+  fn test_redactor() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let mut interpreter = Interpreter::with_engine(*engine);
+      let recorder = Rc::new(RefCell::new(Recorder::default()));
+      interpreter.set_observer(recorder.clone());
+      interpreter.set_redactor(|text: &str| text.replace("hunter2", "*****"));
+      interpreter.register_fn("login", |password: String| -> Result<(), MonkeyError> {
+        Err(MonkeyError::runtime(format!("wrong password: {}", password)))
+      });
+      let secret = "let secret = \"hunter2\"; puts([secret]);";
+      assert_eq!(interpreter.eval_str(secret), Ok(Object::Null));
+      assert_eq!(recorder.borrow().0.last().map(String::as_str), Some("output [*****]"));
+      let error = interpreter.eval_str("login(secret)").unwrap_err();
+      assert_eq!(error.to_string(), "1:1: error: wrong password: *****");
+      let error = interpreter.call_function("login", vec!("hunter2".into())).unwrap_err();
+      assert_eq!(error.to_string(), "error: wrong password: *****");
+
+      let mut output = vec!();
+      let input = "secret\n{\"key\": secret}\n:expand \"hunter2\"\nlogin(\"hunter2\")\n";
+      interpreter.repl(input.as_bytes(), &mut output).unwrap();
+      assert_eq!(String::from_utf8(output).unwrap(), [
+        ">> *****\n",
+        ">> {key: *****}\n",
+        ">> \"*****\"\n",
+        ">> ERROR: wrong password: *****\n",
+        ">> \n",
+      ].concat());
+    }
+  }
+
+  #[test]
+  fn test_interpreter_pool() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let engine = *engine;
+      let pool = InterpreterPool::new(1, move || {
+        let mut interpreter = Interpreter::with_engine(engine);
+        interpreter.register_fn("double", |x: i64| Ok(x * 2));
+        interpreter.eval_str("let count = 1; let bump = fn() { double(count) };")?;
+        interpreter.eval_str("let twice = macro(x) { quote(unquote(x) * 2) };")?;
+        Ok(interpreter)
+      }).unwrap();
+      assert_eq!(pool.idle(), 1);
+
+      let mut first = pool.get().unwrap();
+      assert_eq!(pool.idle(), 0);
+      first.eval_str("count = 5; let secret = twice(21); let twice = 0;").unwrap();
+      assert_eq!(first.eval_str("[bump(), secret]"), Ok(Object::from(vec!(10, 42))));
+      // with none idle, another is made rather than waiting
+      let mut second = pool.get().unwrap();
+      assert_eq!(second.eval_str("bump()"), Ok(Object::Integer(2)));
+      drop(first);
+      drop(second);
+      assert_eq!(pool.idle(), 1);
+
+      let mut reused = pool.get().unwrap();
+      assert_eq!(reused.get_global("secret"), None);
+      assert_eq!(reused.eval_str("[bump(), twice(2)]"), Ok(Object::from(vec!(2, 4))));
+    }
+    let failing = InterpreterPool::new(1, || Interpreter::new().eval_str("@").map(|_| Interpreter::new()));
+    assert!(failing.is_err());
+  }
+
+  #[test]
+  fn test_conversions() {
+    let floats = Object::Array(vec!(Object::Float(1.5), Object::Null));
+    assert_eq!(Object::from(vec!(Some(1.5), None)), floats);
+    assert_eq!(Vec::<i64>::try_from(Object::from(vec!(1, 2))), Ok(vec!(1, 2)));
+    assert_eq!(i64::try_from(Object::from(true)), Err(MonkeyError::runtime(String::from(
+      "expected INTEGER, got BOOLEAN"
+    ))));
+    assert_eq!(Vec::<String>::try_from(Object::from(vec!("a", "b"))).unwrap(), vec!("a", "b"));
+  }
+}
@@ -0,0 +1,546 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::ast::*;
+
+/// A problem with the JSON text, or with the AST it describes. AST errors start with the path to
+/// the offending value, such as `statements[0].value`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct JsonError(pub String);
+
+impl fmt::Display for JsonError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+type JsonResult<T> = Result<T, JsonError>;
+
+impl Program {
+  /// Builds a program from a JSON description of its AST, so that a separately written parser
+  /// can hand its output to the evaluator or compiler. The top level is an object with a
+  /// `statements` array; each statement and expression is an object whose `type` says which
+  /// fields it has:
+  ///
+  /// - statements: `let` and `assign` (`name`, `value`), `return` (`value`), and `expression`
+  ///   (`expression`)
+  /// - `identifier` (`name`); `integer`, `float`, `string` and `boolean` (`value`)
+  /// - `array` (`elements`) and `hash` (`pairs`, an array of `[key, value]` arrays)
+  /// - `prefix` (`operator`, `right`) and `infix` (`operator`, `left`, `right`), with operators
+  ///   written as in Monkey source, such as `"-"` or `"<="`
+  /// - `if` (`condition`, `consequence`, and an optional `alternative`) and `while`
+  ///   (`condition`, `body`), where blocks are arrays of statements
+  /// - `function` and `macro` (`parameters`, an array of names, and `body`); a function may
+  ///   also have a `name`, to call itself by
+  /// - `call` (`function`, `arguments`), `index` (`left`, `index`) and `slice` (`left`, and
+  ///   optional `start` and `end`)
+  ///
+  /// Optional fields may be left out or `null`.
+  pub fn from_json(input: &str) -> Result<Program, JsonError> {
+    let json = JsonParser::new(input).parse_document()?;
+    Ok(Program { statements: statements(field(&json, "statements", "")?, "statements")? })
+  }
+}
+
+// Numbers keep their text, so that integers can be read exactly.
+#[derive(Debug, PartialEq, Clone)]
+enum Json {
+  Null,
+  Boolean(bool),
+  Number(String),
+  Str(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  fn type_name(&self) -> &'static str {
+    match self {
+      Json::Null => "null",
+      Json::Boolean(_) => "a boolean",
+      Json::Number(_) => "a number",
+      Json::Str(_) => "a string",
+      Json::Array(_) => "an array",
+      Json::Object(_) => "an object",
+    }
+  }
+}
+
+struct JsonParser<'a> {
+  input: &'a str,
+  chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> JsonParser<'a> {
+  fn new(input: &'a str) -> Self {
+    JsonParser { input, chars: input.char_indices().peekable() }
+  }
+
+  fn parse_document(&mut self) -> JsonResult<Json> {
+    let value = self.parse_value()?;
+    self.skip_whitespace();
+    match self.chars.peek() {
+      None => Ok(value),
+      Some(_) => Err(self.error("expected the end of the input")),
+    }
+  }
+
+  fn error(&mut self, message: &str) -> JsonError {
+    let offset = self.chars.peek().map_or(self.input.len(), |(i, _)| *i);
+    JsonError(format!("invalid JSON at byte {}: {}", offset, message))
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.chars.peek().is_some_and(|(_, c)| c.is_ascii_whitespace()) {
+      self.chars.next();
+    }
+  }
+
+  fn expect(&mut self, expected: char) -> JsonResult<()> {
+    self.skip_whitespace();
+    match self.chars.peek() {
+      Some((_, c)) if *c == expected => {
+        self.chars.next();
+        Ok(())
+      },
+      _ => Err(self.error(&format!("expected `{}`", expected))),
+    }
+  }
+
+  fn parse_value(&mut self) -> JsonResult<Json> {
+    self.skip_whitespace();
+    match self.chars.peek().map(|(_, c)| *c) {
+      Some('{') => self.parse_object(),
+      Some('[') => {
+        self.chars.next();
+        let elements = self.parse_sequence(']', |p| p.parse_value())?;
+        Ok(Json::Array(elements))
+      },
+      Some('"') => Ok(Json::Str(self.parse_string()?)),
+      Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+      Some(c) if c.is_ascii_alphabetic() => {
+        let start = self.chars.peek().map(|(i, _)| *i).unwrap();
+        let mut end = start;
+        while let Some((i, c)) = self.chars.peek().copied() {
+          if !c.is_ascii_alphabetic() {
+            break;
+          }
+          end = i + c.len_utf8();
+          self.chars.next();
+        }
+        match &self.input[start..end] {
+          "true" => Ok(Json::Boolean(true)),
+          "false" => Ok(Json::Boolean(false)),
+          "null" => Ok(Json::Null),
+          word => {
+            Err(JsonError(format!("invalid JSON at byte {}: unknown literal `{}`", start, word)))
+          },
+        }
+      },
+      _ => Err(self.error("expected a value")),
+    }
+  }
+
+  fn parse_object(&mut self) -> JsonResult<Json> {
+    self.chars.next();
+    let pairs = self.parse_sequence('}', |p| {
+      p.skip_whitespace();
+      let key = p.parse_string()?;
+      p.expect(':')?;
+      Ok((key, p.parse_value()?))
+    })?;
+    Ok(Json::Object(pairs))
+  }
+
+  // Parses comma-separated items up to the closing char, after the opening one is consumed.
+  fn parse_sequence<T>(
+    &mut self, close: char, mut item: impl FnMut(&mut Self) -> JsonResult<T>,
+  ) -> JsonResult<Vec<T>> {
+    let mut items = vec!();
+    self.skip_whitespace();
+    if self.chars.peek().is_some_and(|(_, c)| *c == close) {
+      self.chars.next();
+      return Ok(items);
+    }
+    loop {
+      items.push(item(self)?);
+      self.skip_whitespace();
+      match self.chars.next() {
+        Some((_, ',')) => {},
+        Some((_, c)) if c == close => return Ok(items),
+        _ => return Err(self.error(&format!("expected `,` or `{}`", close))),
+      }
+    }
+  }
+
+  fn parse_string(&mut self) -> JsonResult<String> {
+    match self.chars.peek() {
+      Some((_, '"')) => self.chars.next(),
+      _ => return Err(self.error("expected a string")),
+    };
+    let mut s = String::new();
+    loop {
+      match self.chars.next().map(|(_, c)| c) {
+        None => return Err(self.error("unterminated string")),
+        Some('"') => return Ok(s),
+        Some('\\') => match self.chars.next().map(|(_, c)| c) {
+          Some('"') => s.push('"'),
+          Some('\\') => s.push('\\'),
+          Some('/') => s.push('/'),
+          Some('b') => s.push('\u{8}'),
+          Some('f') => s.push('\u{c}'),
+          Some('n') => s.push('\n'),
+          Some('r') => s.push('\r'),
+          Some('t') => s.push('\t'),
+          Some('u') => s.push(self.parse_unicode_escape()?),
+          _ => return Err(self.error("invalid escape in string")),
+        },
+        Some(c) if (c as u32) < 0x20 => return Err(self.error("control character in string")),
+        Some(c) => s.push(c),
+      }
+    }
+  }
+
+  // Called after `\u`. Characters outside the basic multilingual plane are written as a pair
+  // of escaped UTF-16 surrogates.
+  fn parse_unicode_escape(&mut self) -> JsonResult<char> {
+    let high = self.parse_hex4()?;
+    if !(0xD800..0xDC00).contains(&high) {
+      return char::from_u32(high).ok_or_else(|| self.error("invalid unicode escape"));
+    }
+    let escape: String = self.chars.by_ref().take(2).map(|(_, c)| c).collect();
+    if escape != "\\u" {
+      return Err(self.error("expected a low surrogate"));
+    }
+    let low = self.parse_hex4()?;
+    if !(0xDC00..0xE000).contains(&low) {
+      return Err(self.error("expected a low surrogate"));
+    }
+    let code = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+    char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+  }
+
+  fn parse_hex4(&mut self) -> JsonResult<u32> {
+    let mut code = 0;
+    for _ in 0..4 {
+      match self.chars.next().and_then(|(_, c)| c.to_digit(16)) {
+        Some(digit) => code = code * 16 + digit,
+        None => return Err(self.error("invalid unicode escape")),
+      }
+    }
+    Ok(code)
+  }
+
+  fn parse_number(&mut self) -> JsonResult<Json> {
+    let mut text = String::new();
+    while let Some((_, c)) = self.chars.peek().copied() {
+      if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+        break;
+      }
+      text.push(c);
+      self.chars.next();
+    }
+    match text.parse::<f64>() {
+      Ok(_) => Ok(Json::Number(text)),
+      Err(_) => Err(self.error(&format!("invalid number `{}`", text))),
+    }
+  }
+}
+
+fn field<'a>(json: &'a Json, name: &str, path: &str) -> JsonResult<&'a Json> {
+  optional_field(json, name, path)?
+    .ok_or_else(|| JsonError(format!("{}: missing field `{}`", display_path(path), name)))
+}
+
+fn optional_field<'a>(json: &'a Json, name: &str, path: &str) -> JsonResult<Option<&'a Json>> {
+  match json {
+    Json::Object(pairs) => Ok(pairs.iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value)
+      .filter(|value| **value != Json::Null)),
+    other => Err(expected("an object", other, path)),
+  }
+}
+
+fn display_path(path: &str) -> &str {
+  if path.is_empty() { "top level" } else { path }
+}
+
+fn expected(what: &str, got: &Json, path: &str) -> JsonError {
+  JsonError(format!("{}: expected {}, got {}", display_path(path), what, got.type_name()))
+}
+
+fn join_path(path: &str, name: &str) -> String {
+  if path.is_empty() { String::from(name) } else { format!("{}.{}", path, name) }
+}
+
+fn array<'a>(json: &'a Json, path: &str) -> JsonResult<&'a [Json]> {
+  match json {
+    Json::Array(elements) => Ok(elements),
+    other => Err(expected("an array", other, path)),
+  }
+}
+
+fn string(json: &Json, path: &str) -> JsonResult<String> {
+  match json {
+    Json::Str(s) => Ok(s.clone()),
+    other => Err(expected("a string", other, path)),
+  }
+}
+
+fn string_field(json: &Json, name: &str, path: &str) -> JsonResult<String> {
+  string(field(json, name, path)?, &join_path(path, name))
+}
+
+fn statements(json: &Json, path: &str) -> JsonResult<Vec<Statement>> {
+  array(json, path)?.iter().enumerate()
+    .map(|(i, s)| statement(s, &format!("{}[{}]", path, i)))
+    .collect()
+}
+
+fn block(json: &Json, name: &str, path: &str) -> JsonResult<BlockStatement> {
+  let block_path = join_path(path, name);
+  Ok(BlockStatement { statements: statements(field(json, name, path)?, &block_path)? })
+}
+
+fn statement(json: &Json, path: &str) -> JsonResult<Statement> {
+  let value = |name: &str| expression_field(json, name, path);
+  match string_field(json, "type", path)?.as_str() {
+    "let" => Ok(Statement::Let(string_field(json, "name", path)?, value("value")?)),
+    "assign" => Ok(Statement::Assign(string_field(json, "name", path)?, value("value")?)),
+    "return" => Ok(Statement::Return(value("value")?)),
+    "expression" => Ok(Statement::Expression(value("expression")?)),
+    other => Err(JsonError(format!("{}: unknown statement type `{}`", path, other))),
+  }
+}
+
+fn expression_field(json: &Json, name: &str, path: &str) -> JsonResult<Expression> {
+  expression(field(json, name, path)?, &join_path(path, name))
+}
+
+fn boxed_field(json: &Json, name: &str, path: &str) -> JsonResult<Box<Expression>> {
+  expression_field(json, name, path).map(Box::new)
+}
+
+fn optional_boxed_field(json: &Json, name: &str, path: &str) -> JsonResult<Option<Box<Expression>>> {
+  match optional_field(json, name, path)? {
+    Some(value) => Ok(Some(Box::new(expression(value, &join_path(path, name))?))),
+    None => Ok(None),
+  }
+}
+
+fn expressions(json: &Json, path: &str) -> JsonResult<Vec<Expression>> {
+  array(json, path)?.iter().enumerate()
+    .map(|(i, e)| expression(e, &format!("{}[{}]", path, i)))
+    .collect()
+}
+
+fn parameters(json: &Json, path: &str) -> JsonResult<Vec<String>> {
+  let parameters_path = join_path(path, "parameters");
+  array(field(json, "parameters", path)?, &parameters_path)?.iter().enumerate()
+    .map(|(i, p)| string(p, &format!("{}[{}]", parameters_path, i)))
+    .collect()
+}
+
+fn expression(json: &Json, path: &str) -> JsonResult<Expression> {
+  let kind = string_field(json, "type", path)?;
+  let value_path = join_path(path, "value");
+  let expression = match kind.as_str() {
+    "identifier" => Expression::Identifier(string_field(json, "name", path)?),
+    "integer" => match field(json, "value", path)? {
+      Json::Number(text) => match text.parse() {
+        Ok(i) => Expression::IntegerLiteral(i),
+        Err(_) => {
+          return Err(JsonError(format!("{}: `{}` is not a 64-bit integer", value_path, text)));
+        },
+      },
+      other => return Err(expected("a number", other, &value_path)),
+    },
+    "float" => match field(json, "value", path)? {
+      Json::Number(text) => {
+        Expression::FloatLiteral(text.parse().expect("numbers are checked when parsed"))
+      },
+      other => return Err(expected("a number", other, &value_path)),
+    },
+    "string" => Expression::StringLiteral(string_field(json, "value", path)?),
+    "boolean" => match field(json, "value", path)? {
+      Json::Boolean(b) => Expression::Boolean(*b),
+      other => return Err(expected("a boolean", other, &value_path)),
+    },
+    "array" => {
+      let elements = field(json, "elements", path)?;
+      Expression::ArrayLiteral(expressions(elements, &join_path(path, "elements"))?)
+    },
+    "hash" => {
+      let pairs_path = join_path(path, "pairs");
+      let mut pairs = vec!();
+      for (i, pair) in array(field(json, "pairs", path)?, &pairs_path)?.iter().enumerate() {
+        let pair_path = format!("{}[{}]", pairs_path, i);
+        match expressions(pair, &pair_path)?.as_slice() {
+          [key, value] => pairs.push((key.clone(), value.clone())),
+          _ => return Err(JsonError(format!("{}: expected a [key, value] pair", pair_path))),
+        }
+      }
+      Expression::HashLiteral(pairs)
+    },
+    "prefix" => {
+      let symbol = string_field(json, "operator", path)?;
+      let operator = match symbol.as_str() {
+        "!" => PrefixOperator::Bang,
+        "-" => PrefixOperator::Minus,
+        _ => return Err(unknown_operator(&symbol, path)),
+      };
+      Expression::Prefix(operator, boxed_field(json, "right", path)?)
+    },
+    "infix" => {
+      let symbol = string_field(json, "operator", path)?;
+      let operator = infix_operator(&symbol).ok_or_else(|| unknown_operator(&symbol, path))?;
+      Expression::Infix(operator, boxed_field(json, "left", path)?, boxed_field(json, "right", path)?)
+    },
+    "if" => Expression::If {
+      condition: boxed_field(json, "condition", path)?,
+      consequence: block(json, "consequence", path)?,
+      alternative: match optional_field(json, "alternative", path)? {
+        Some(_) => Some(block(json, "alternative", path)?),
+        None => None,
+      },
+    },
+    "while" => Expression::While {
+      condition: boxed_field(json, "condition", path)?,
+      body: block(json, "body", path)?,
+    },
+    "function" => Expression::FunctionLiteral {
+      name: match optional_field(json, "name", path)? {
+        Some(_) => Some(string_field(json, "name", path)?),
+        None => None,
+      },
+      parameters: parameters(json, path)?,
+      body: block(json, "body", path)?,
+    },
+    "macro" => Expression::MacroLiteral {
+      parameters: parameters(json, path)?,
+      body: block(json, "body", path)?,
+    },
+    "call" => Expression::Call {
+      function: boxed_field(json, "function", path)?,
+      arguments: expressions(field(json, "arguments", path)?, &join_path(path, "arguments"))?,
+    },
+    "index" => Expression::Index {
+      left: boxed_field(json, "left", path)?,
+      index: boxed_field(json, "index", path)?,
+    },
+    "slice" => Expression::Slice {
+      left: boxed_field(json, "left", path)?,
+      start: optional_boxed_field(json, "start", path)?,
+      end: optional_boxed_field(json, "end", path)?,
+    },
+    other => return Err(JsonError(format!("{}: unknown expression type `{}`", path, other))),
+  };
+  Ok(expression)
+}
+
+fn unknown_operator(symbol: &str, path: &str) -> JsonError {
+  JsonError(format!("{}: unknown operator `{}`", join_path(path, "operator"), symbol))
+}
+
+fn infix_operator(symbol: &str) -> Option<InfixOperator> {
+  let operators = [
+    InfixOperator::Plus, InfixOperator::Minus, InfixOperator::Asterisk, InfixOperator::Slash,
+    InfixOperator::TildeSlash, InfixOperator::Percent, InfixOperator::Ampersand,
+    InfixOperator::Pipe, InfixOperator::Caret, InfixOperator::ShiftLeft, InfixOperator::ShiftRight,
+    InfixOperator::GT, InfixOperator::LT, InfixOperator::GtEq, InfixOperator::LtEq,
+    InfixOperator::Eq, InfixOperator::NotEq, InfixOperator::And, InfixOperator::Or,
+  ];
+  operators.iter().copied().find(|o| o.symbol() == symbol)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap()
+  }
+
+  fn error(json: &str) -> String {
+    Program::from_json(json).unwrap_err().0
+  }
+
+  #[test]
+  fn test_from_json() {
+    let json = r#"{"statements": [
+      {"type": "let", "name": "add", "value": {"type": "function", "parameters": ["a", "b"], "body": [
+        {"type": "return", "value": {"type": "infix", "operator": "+",
+          "left": {"type": "identifier", "name": "a"}, "right": {"type": "identifier", "name": "b"}}}
+      ]}},
+      {"type": "assign", "name": "x", "value": {"type": "prefix", "operator": "-",
+        "right": {"type": "float", "value": 2.5e0}}},
+      {"type": "expression", "expression": {"type": "if",
+        "condition": {"type": "boolean", "value": true},
+        "consequence": [{"type": "expression",
+          "expression": {"type": "string", "value": "tab\tand \u00e9 \ud83d\ude00"}}],
+        "alternative": null}},
+      {"type": "expression", "expression": {"type": "while",
+        "condition": {"type": "boolean", "value": false}, "body": []}},
+      {"type": "expression", "expression": {"type": "call",
+        "function": {"type": "identifier", "name": "add"}, "arguments": [
+        {"type": "index", "left": {"type": "array", "elements": [{"type": "integer", "value": 1}]},
+          "index": {"type": "integer", "value": 0}},
+        {"type": "slice", "left": {"type": "identifier", "name": "s"},
+          "end": {"type": "integer", "value": 2}},
+        {"type": "hash", "pairs": [
+          [{"type": "string", "value": "k"}, {"type": "macro", "parameters": [], "body": []}]
+        ]}
+      ]}}
+    ]}"#;
+    let expected = parse(r#"
+      let add = fn(a, b) { return a + b; };
+      x = -2.5;
+      if (true) { "tab	and é 😀" };
+      while (false) {};
+      add([1][0], s[:2], {"k": macro() {}})
+    "#);
+    assert_eq!(Program::from_json(json), Ok(expected));
+    assert_eq!(Program::from_json(r#" {"statements": []} "#), Ok(Program { statements: vec!() }));
+    let named = r#"{"statements": [{"type": "expression", "expression":
+      {"type": "function", "name": "f", "parameters": [], "body": []}}]}"#;
+    assert_eq!(Program::from_json(named), Ok(parse("fn f() {}")));
+    let floor = r#"{"statements": [{"type": "expression", "expression": {"type": "infix",
+      "operator": "~/", "left": {"type": "integer", "value": 7}, "right": {"type": "integer", "value": 2}}}]}"#;
+    assert_eq!(Program::from_json(floor), Ok(parse("7 ~/ 2")));
+  }
+
+  #[test]
+  fn test_from_json_errors() {
+    let cases = [
+      ("", "invalid JSON at byte 0: expected a value"),
+      (r#"{"statements": [1,]}"#, "invalid JSON at byte 18: expected a value"),
+      (r#"{"statements": []} x"#, "invalid JSON at byte 19: expected the end of the input"),
+      (r#"{"statements": "#, "invalid JSON at byte 15: expected a value"),
+      (r#"{"statements": nul}"#, "invalid JSON at byte 15: unknown literal `nul`"),
+      (r#"["statements"]"#, "top level: expected an object, got an array"),
+      (r#"{}"#, "top level: missing field `statements`"),
+      (r#"{"statements": [{"type": "print"}]}"#, "statements[0]: unknown statement type `print`"),
+      (r#"{"statements": [{"type": "let", "name": 1}]}"#, "statements[0].name: expected a string, got a number"),
+      (
+        r#"{"statements": [{"type": "return", "value": {"type": "integer", "value": 1.5}}]}"#,
+        "statements[0].value.value: `1.5` is not a 64-bit integer",
+      ),
+      (
+        r#"{"statements": [{"type": "expression", "expression": {"type": "infix", "operator": "**"}}]}"#,
+        "statements[0].expression.operator: unknown operator `**`",
+      ),
+      (
+        r#"{"statements": [{"type": "expression", "expression": {"type": "while", "condition":
+          {"type": "boolean", "value": true}, "body": [{"type": "expression"}]}}]}"#,
+        "statements[0].expression.body[0]: missing field `expression`",
+      ),
+    ];
+    for (json, expected) in cases.iter() {
+      assert_eq!(error(json), *expected, "json {:?}", json);
+    }
+  }
+}
@@ -1,119 +1,357 @@
+use std::error::Error;
+use std::fmt;
 use std::iter::*;
-use std::str::Chars;
+use std::str::CharIndices;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
   Illegal,
   EOF,
 
   Ident(String),
-  Int(u32),
+  Int(i64),
+  Float(f64),
+  Str(String),
 
   Assign,
   Plus,
   Minus,
   Asterisk,
   Slash,
+  TildeSlash,
+  Bang,
   GT,
   LT,
+  GtEq,
+  LtEq,
+  And,
+  Or,
+  Percent,
+  Ampersand,
+  Pipe,
+  Caret,
+  ShiftLeft,
+  ShiftRight,
   Comma,
+  Colon,
   Semicolon,
   LParen,
   RParen,
   LBrace,
   RBrace,
+  LBracket,
+  RBracket,
   Eq,
   NotEq,
 
   Function,
+  Macro,
   Let,
   True,
   False,
   If,
   Else,
+  While,
   Return,
+  Struct,
+}
+
+/// Where a token starts in the source. Lines and columns count from 1; columns count chars.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+  pub line: usize,
+  pub column: usize,
+  pub byte_offset: usize,
+}
+
+/// Why the lexer produced a `Token::Illegal`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexErrorKind {
+  UnexpectedChar(char),
+  /// An integer too large for an i64, or a float too large to be finite.
+  NumberOutOfRange(String),
+  InvalidEscape(char),
+  UnterminatedString,
+  UnterminatedComment,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexError {
+  pub kind: LexErrorKind,
+  pub span: Span,
+}
+
+impl fmt::Display for LexError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}: error: ", self.span.line, self.span.column)?;
+    match &self.kind {
+      LexErrorKind::UnexpectedChar(c) => write!(f, "unexpected character {:?}", c),
+      LexErrorKind::NumberOutOfRange(number) => write!(f, "number {} is out of range", number),
+      LexErrorKind::InvalidEscape(c) => write!(f, "unknown escape sequence \\{}", c),
+      LexErrorKind::UnterminatedString => write!(f, "unterminated string"),
+      LexErrorKind::UnterminatedComment => write!(f, "unterminated block comment"),
+    }
+  }
+}
+
+impl Error for LexError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+  pub token: Token,
+  pub span: Span,
 }
 
 type CharTest = fn(&char) -> bool;
 
 pub struct Lexer<'a> {
-  chars: Peekable<Chars<'a>>,
+  input: &'a str,
+  // the byte offset of `chars` in `input`
+  offset: usize,
+  chars: Peekable<CharIndices<'a>>,
+  line: usize,
+  column: usize,
+  errors: Vec<LexError>,
 }
 
 impl<'a> Lexer<'a> {
   pub fn new(input: &'a str) -> Self {
+    Lexer::starting_at(input, Span { line: 1, column: 1, byte_offset: 0 })
+  }
+
+  /// A lexer that starts reading `input` at `span`, which must be the start of a token.
+  pub fn starting_at(input: &'a str, span: Span) -> Self {
     Lexer {
-      chars: input.chars().peekable(),
+      input,
+      offset: span.byte_offset,
+      chars: input[span.byte_offset..].char_indices().peekable(),
+      line: span.line,
+      column: span.column,
+      errors: vec!(),
     }
   }
 
+  /// Why each `Token::Illegal` produced so far was illegal, in order.
+  pub fn errors(&self) -> &[LexError] {
+    &self.errors
+  }
+
+  fn illegal(&mut self, kind: LexErrorKind, span: Span) -> Token {
+    self.errors.push(LexError { kind, span });
+    Token::Illegal
+  }
+
+  fn peek_char(&mut self) -> Option<&char> {
+    self.chars.peek().map(|(_, c)| c)
+  }
+
+  fn next_char(&mut self) -> Option<char> {
+    let (_, c) = self.chars.next()?;
+    if c == '\n' {
+      self.line += 1;
+      self.column = 1;
+    } else {
+      self.column += 1;
+    }
+    Some(c)
+  }
+
+  /// The position of the next unread char.
+  pub fn span(&mut self) -> Span {
+    let offset = self.offset;
+    let byte_offset = self.chars.peek().map_or(self.input.len(), |(i, _)| offset + *i);
+    Span { line: self.line, column: self.column, byte_offset }
+  }
+
   fn accumulate_while(&mut self, test: CharTest, start_with: char) -> String {
-    let mut acc = vec!(start_with);
-    while let Some(peek_c) = self.chars.peek() {
+    let mut acc = start_with.to_string();
+    self.push_while(test, &mut acc);
+    acc
+  }
+
+  fn push_while(&mut self, test: CharTest, acc: &mut String) {
+    while let Some(peek_c) = self.peek_char() {
       if !test(peek_c) {
         break;
       }
       acc.push(*peek_c);
-      self.chars.next();
+      self.next_char();
     }
-    acc.iter().collect()
   }
-}
-
-impl<'a> Iterator for Lexer<'a> {
-  type Item = Token;
 
-  fn next(&mut self) -> Option<Token> {
-    let mut c = self.chars.next()?;
+  // Reads an integer, or a float if the digits are followed by a fractional part (`3.14`) or an
+  // exponent (`1e-9`). A `.` or `e` not followed by digits is not part of the number.
+  fn read_number(&mut self, start_with: char) -> Result<Token, LexErrorKind> {
+    let mut num_str = self.accumulate_while(is_monkey_digit, start_with);
+    let mut is_float = false;
 
-    // eat whitespace
-    while is_monkey_whitespace(&c) {
-      c = self.chars.next()?;
+    if self.peek_char() == Some(&'.') && self.peek_second_char().is_some_and(|c| is_monkey_digit(&c)) {
+      self.next_char();
+      num_str.push('.');
+      self.push_while(is_monkey_digit, &mut num_str);
+      is_float = true;
     }
-    let c = c;
-
-    match c {
-      ';' => Some(Token::Semicolon),
-      '(' => Some(Token::LParen),
-      ')' => Some(Token::RParen),
-      ',' => Some(Token::Comma),
-      '+' => Some(Token::Plus),
-      '-' => Some(Token::Minus),
-      '*' => Some(Token::Asterisk),
-      '/' => Some(Token::Slash),
-      '>' => Some(Token::GT),
-      '<' => Some(Token::LT),
-      '{' => Some(Token::LBrace),
-      '}' => Some(Token::RBrace),
-      '=' => {  // TODO: consider handling two-char tokens more generally.
-        if let Some('=') = self.chars.peek() {
-          self.chars.next();
-          Some(Token::Eq)
-        } else {
-          Some(Token::Assign)
+
+    if let Some('e') | Some('E') = self.peek_char() {
+      let mut lookahead = self.chars.clone();
+      lookahead.next();
+      let sign = match lookahead.peek() {
+        Some((_, c)) if *c == '+' || *c == '-' => {
+          let sign = *c;
+          lookahead.next();
+          Some(sign)
+        },
+        _ => None,
+      };
+      if lookahead.peek().is_some_and(|(_, c)| is_monkey_digit(c)) {
+        self.next_char();
+        num_str.push('e');
+        if let Some(sign) = sign {
+          self.next_char();
+          num_str.push(sign);
         }
+        self.push_while(is_monkey_digit, &mut num_str);
+        is_float = true;
+      }
+    }
+
+    // only well-formed digits were accumulated, so an integer can fail only on overflow
+    if is_float {
+      match num_str.parse::<f64>() {
+        Ok(num) if num.is_finite() => Ok(Token::Float(num)),
+        _ => Err(LexErrorKind::NumberOutOfRange(num_str)),
+      }
+    } else {
+      num_str.parse::<i64>().map(Token::Int).map_err(|_| LexErrorKind::NumberOutOfRange(num_str))
+    }
+  }
+
+  // The char after the next unread one.
+  fn peek_second_char(&self) -> Option<char> {
+    self.chars.clone().nth(1).map(|(_, c)| c)
+  }
+
+  // Skips `//` line comments and `/* */` block comments along with whitespace. An unterminated
+  // block comment consumes the rest of the input and yields the span of its opening `/*`.
+  fn skip_whitespace_and_comments(&mut self) -> Result<(), Span> {
+    loop {
+      match (self.peek_char().copied(), self.peek_second_char()) {
+        (Some(c), _) if is_monkey_whitespace(&c) => {
+          self.next_char();
+        },
+        (Some('/'), Some('/')) => {
+          while let Some(c) = self.next_char() {
+            if c == '\n' {
+              break;
+            }
+          }
+        },
+        (Some('/'), Some('*')) => {
+          let start = self.span();
+          self.next_char();
+          self.next_char();
+          loop {
+            match self.next_char() {
+              None => return Err(start),
+              Some('*') if self.peek_char() == Some(&'/') => {
+                self.next_char();
+                break;
+              },
+              _ => {},
+            }
+          }
+        },
+        _ => return Ok(()),
+      }
+    }
+  }
+
+  // For a char that may start a two-char token: the token paired with the next char in `pairs`,
+  // consuming that char, or `one` if none matches.
+  fn two_char(&mut self, pairs: &[(char, Token)], one: Token) -> Token {
+    let next = self.peek_char().copied();
+    match pairs.iter().find(|(second, _)| Some(*second) == next) {
+      Some((_, two)) => {
+        self.next_char();
+        two.clone()
       },
-      '!' => {
-        if let Some('=') = self.chars.peek() {
-          self.chars.next();
-          Some(Token::NotEq)
-        } else {
-          Some(Token::Illegal)
-        }
+      None => one,
+    }
+  }
+
+  // Called after the opening quote has been consumed. A bad escape still reads through to the
+  // closing quote so that the rest of the string isn't lexed as code.
+  fn read_string(&mut self) -> Result<Token, LexErrorKind> {
+    let mut acc = String::new();
+    let mut invalid = None;
+    loop {
+      match self.next_char() {
+        None => return Err(LexErrorKind::UnterminatedString),
+        Some('"') => return invalid.map_or(Ok(Token::Str(acc)), |c| Err(LexErrorKind::InvalidEscape(c))),
+        Some('\\') => match self.next_char() {
+          Some('n') => acc.push('\n'),
+          Some('t') => acc.push('\t'),
+          Some('"') => acc.push('"'),
+          Some('\\') => acc.push('\\'),
+          None => return Err(LexErrorKind::UnterminatedString),
+          Some(c) => invalid = invalid.or(Some(c)),
+        },
+        Some(c) => acc.push(c),
+      }
+    }
+  }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+  type Item = SpannedToken;
+
+  fn next(&mut self) -> Option<SpannedToken> {
+    if let Err(span) = self.skip_whitespace_and_comments() {
+      let token = self.illegal(LexErrorKind::UnterminatedComment, span);
+      return Some(SpannedToken { token, span });
+    }
+    let span = self.span();
+    let c = self.next_char()?;
+
+    let token = match c {
+      ';' => Token::Semicolon,
+      '(' => Token::LParen,
+      ')' => Token::RParen,
+      ',' => Token::Comma,
+      ':' => Token::Colon,
+      '+' => Token::Plus,
+      '-' => Token::Minus,
+      '*' => Token::Asterisk,
+      '/' => Token::Slash,
+      '~' if self.peek_char() == Some(&'/') => {
+        self.next_char();
+        Token::TildeSlash
       },
+      '%' => Token::Percent,
+      '^' => Token::Caret,
+      '>' => self.two_char(&[('=', Token::GtEq), ('>', Token::ShiftRight)], Token::GT),
+      '<' => self.two_char(&[('=', Token::LtEq), ('<', Token::ShiftLeft)], Token::LT),
+      '{' => Token::LBrace,
+      '}' => Token::RBrace,
+      '[' => Token::LBracket,
+      ']' => Token::RBracket,
+      '"' => self.read_string().unwrap_or_else(|kind| self.illegal(kind, span)),
+      '=' => self.two_char(&[('=', Token::Eq)], Token::Assign),
+      '!' => self.two_char(&[('=', Token::NotEq)], Token::Bang),
+      '&' => self.two_char(&[('&', Token::And)], Token::Ampersand),
+      '|' => self.two_char(&[('|', Token::Or)], Token::Pipe),
       c_ => {
         if is_monkey_letter(&c_) {  // read identifier
           let ident_str = self.accumulate_while(is_monkey_letter, c_);
-          parse_keyword(&ident_str).or(Some(Token::Ident(ident_str)))
+          parse_keyword(&ident_str).unwrap_or(Token::Ident(ident_str))
         } else if is_monkey_digit(&c_){
-          let num_str = self.accumulate_while(is_monkey_digit, c_);
-          let num = num_str.parse::<u32>().expect("not a number");
-          Some(Token::Int(num))
+          self.read_number(c_).unwrap_or_else(|kind| self.illegal(kind, span))
         } else {
-          Some(Token::Illegal)
+          self.illegal(LexErrorKind::UnexpectedChar(c_), span)
         }
       }
-    }
+    };
+    Some(SpannedToken { token, span })
   }
 }
 
@@ -129,14 +367,131 @@ fn is_monkey_whitespace(c: &char) -> bool {
   c.is_whitespace()
 }
 
+/// The part of a token stream that changed between two versions of a source: old tokens
+/// `start..old_end` were replaced by `tokens`, which occupy `start..new_end` in the new stream.
+#[derive(Debug, PartialEq)]
+pub struct TokenDiff {
+  pub start: usize,
+  pub old_end: usize,
+  pub new_end: usize,
+  pub tokens: Vec<SpannedToken>,
+  /// Where the first token after the edit starts, in the old source and in the new one. The old
+  /// tokens from `old_end` on are unchanged but for moving the same way.
+  pub moved: Option<(Span, Span)>,
+}
+
+impl TokenDiff {
+  /// The new token stream, given the old one the diff was made against.
+  pub fn apply(&self, old_tokens: &[SpannedToken]) -> Vec<SpannedToken> {
+    let mut tokens = old_tokens[..self.start].to_vec();
+    tokens.extend(self.tokens.iter().cloned());
+    tokens.extend(old_tokens[self.old_end..].iter().map(|t| SpannedToken {
+      token: t.token.clone(),
+      span: self.shift(t.span).expect("tokens after the edit move forward with it"),
+    }));
+    tokens
+  }
+
+  fn shift(&self, span: Span) -> Option<Span> {
+    let (from, to) = match self.moved {
+      Some(moved) => moved,
+      None => return Some(span),
+    };
+    let moved = |n: usize, from: usize, to: usize| (n + to).checked_sub(from);
+    // only the rest of the line the edit ended on moves sideways
+    let column = if span.line == from.line {
+      moved(span.column, from.column, to.column)?
+    } else {
+      span.column
+    };
+    Some(Span {
+      line: moved(span.line, from.line, to.line)?,
+      column,
+      byte_offset: moved(span.byte_offset, from.byte_offset, to.byte_offset)?,
+    })
+  }
+}
+
+// How many bytes past the end of a token the lexer may look before deciding where it ends: three
+// chars, for the `e+5` after the digits of `1e+5`.
+const LOOKAHEAD: usize = 12;
+
+/// Re-lexes only the part of `new_src` that differs from `old_src`, which `old_tokens` came from.
+pub fn diff_tokens(old_src: &str, new_src: &str, old_tokens: &[SpannedToken]) -> TokenDiff {
+  if old_src == new_src {
+    let end = old_tokens.len();
+    return TokenDiff { start: end, old_end: end, new_end: end, tokens: vec!(), moved: None };
+  }
+
+  let (old_bytes, new_bytes) = (old_src.as_bytes(), new_src.as_bytes());
+  let prefix = old_bytes.iter().zip(new_bytes).take_while(|(o, n)| o == n).count();
+  // the common suffix must not overlap the common prefix in either source
+  let max_suffix = old_src.len().min(new_src.len()) - prefix;
+  let suffix = old_bytes.iter().rev()
+    .zip(new_bytes.iter().rev())
+    .take(max_suffix)
+    .take_while(|(o, n)| o == n)
+    .count();
+
+  // a token is safe from the edit if the lexer never read as far as the edit to find its end
+  let mut start = old_tokens.windows(2)
+    .take_while(|pair| pair[1].span.byte_offset + LOOKAHEAD <= prefix)
+    .count();
+  let resume = old_tokens.get(start)
+    .map_or(Span { line: 1, column: 1, byte_offset: 0 }, |t| t.span);
+
+  // from a token that starts inside the common suffix at the same place as an old one, the rest of
+  // both streams are the same
+  let unchanged = new_src.len() - suffix;
+  let mut old_end = old_tokens.len();
+  let mut moved = None;
+  let mut tokens = vec!();
+  for token in Lexer::starting_at(new_src, resume) {
+    if token.span.byte_offset >= unchanged {
+      let old_offset = token.span.byte_offset + old_src.len() - new_src.len();
+      let found = old_tokens[start..].binary_search_by_key(&old_offset, |t| t.span.byte_offset);
+      if let Ok(i) = found {
+        old_end = start + i;
+        moved = Some((old_tokens[old_end].span, token.span));
+        break;
+      }
+    }
+    tokens.push(token);
+  }
+
+  let same = old_tokens[start..old_end].iter()
+    .zip(tokens.iter())
+    .take_while(|(o, n)| o == n)
+    .count();
+  start += same;
+  tokens.drain(..same);
+  let mut diff = TokenDiff { start, old_end, new_end: 0, tokens, moved };
+  // tokens just before the sync point may have only moved, too
+  while diff.old_end > diff.start {
+    let old = &old_tokens[diff.old_end - 1];
+    match diff.tokens.last() {
+      Some(new) if old.token == new.token && diff.shift(old.span) == Some(new.span) => {
+        diff.tokens.pop();
+        diff.old_end -= 1;
+      },
+      _ => break,
+    }
+  }
+  diff.new_end = diff.start + diff.tokens.len();
+  diff
+}
+
 fn parse_keyword(s: &str) -> Option<Token> {
   match s {
     "let" => Some(Token::Let),
     "fn" => Some(Token::Function),
+    "macro" => Some(Token::Macro),
     "true" => Some(Token::True),
     "false" => Some(Token::False),
     "if" => Some(Token::If),
     "else" => Some(Token::Else),
+    "while" => Some(Token::While),
+    "struct" => Some(Token::Struct),
     "return" => Some(Token::Return),
     _ => None,
   }
@@ -146,6 +501,10 @@ fn parse_keyword(s: &str) -> Option<Token> {
 mod test {
   use super::*;
 
+  fn tokens(input: &str) -> impl Iterator<Item = Token> + '_ {
+    Lexer::new(input).map(|t| t.token)
+  }
+
   #[test]
   fn test_test() {
     println!("I'm a test of the tests");
@@ -153,7 +512,7 @@ mod test {
 
   #[test]
   fn test_lexer_single_chars() {
-    let mut l = Lexer::new("{}+=");
+    let mut l = tokens("{}+=");
     assert_eq!(l.next(), Some(Token::LBrace));
     assert_eq!(l.next(), Some(Token::RBrace));
     assert_eq!(l.next(), Some(Token::Plus));
@@ -161,9 +520,25 @@ mod test {
     assert_eq!(l.next(), None);
   }
 
+  #[test]
+  fn test_lexer_brackets_and_colon() {
+    let l: Vec<Token> = tokens("[]:").collect();
+    assert_eq!(l, vec!(Token::LBracket, Token::RBracket, Token::Colon));
+  }
+
+  #[test]
+  fn test_lexer_two_char_tokens() {
+    let l: Vec<Token> = tokens("<= < >= >== != ! && || & | << >> <<= >>> % ^").collect();
+    assert_eq!(l, vec!(
+      Token::LtEq, Token::LT, Token::GtEq, Token::GtEq, Token::Assign, Token::NotEq, Token::Bang,
+      Token::And, Token::Or, Token::Ampersand, Token::Pipe, Token::ShiftLeft, Token::ShiftRight,
+      Token::ShiftLeft, Token::Assign, Token::ShiftRight, Token::GT, Token::Percent, Token::Caret,
+    ));
+  }
+
     #[test]
   fn test_lexer_ident() {
-    let mut l = Lexer::new("{}+=asd_f=");
+    let mut l = tokens("{}+=asd_f=");
     assert_eq!(l.next(), Some(Token::LBrace));
     assert_eq!(l.next(), Some(Token::RBrace));
     assert_eq!(l.next(), Some(Token::Plus));
@@ -172,18 +547,24 @@ mod test {
     assert_eq!(l.next(), Some(Token::Assign));
     assert_eq!(l.next(), None);
 
-    let mut l = Lexer::new("{}+=asd_f");
+    let mut l = tokens("{}+=asd_f");
     assert_eq!(l.next(), Some(Token::LBrace));
     assert_eq!(l.next(), Some(Token::RBrace));
     assert_eq!(l.next(), Some(Token::Plus));
     assert_eq!(l.next(), Some(Token::Assign));
     assert_eq!(l.next(), Some(Token::Ident(String::from("asd_f"))));
     assert_eq!(l.next(), None);
+
+    let mut l = tokens("macro while macros");
+    assert_eq!(l.next(), Some(Token::Macro));
+    assert_eq!(l.next(), Some(Token::While));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("macros"))));
+    assert_eq!(l.next(), None);
   }
 
   #[test]
   fn test_eat_whitespace() {
-    let mut l = Lexer::new("  {}   +=asd_f  =  ");
+    let mut l = tokens("  {}   +=asd_f  =  ");
     assert_eq!(l.next(), Some(Token::LBrace));
     assert_eq!(l.next(), Some(Token::RBrace));
     assert_eq!(l.next(), Some(Token::Plus));
@@ -195,7 +576,7 @@ mod test {
 
   #[test]
   fn test_some_code() {
-    let mut l = Lexer::new("
+    let mut l = tokens("
       let stuff = fn(x, y) {
         return x + y + 3;
       };
@@ -222,5 +603,161 @@ mod test {
     assert_eq!(l.next(), None);
   }
 
+  #[test]
+  fn test_integer_range() {
+    let mut l = tokens("9223372036854775807 9223372036854775808 -1");
+    assert_eq!(l.next(), Some(Token::Int(i64::MAX)));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), Some(Token::Minus));
+    assert_eq!(l.next(), Some(Token::Int(1)));
+    assert_eq!(l.next(), None);
+  }
+
+  #[test]
+  fn test_floats() {
+    let mut l = tokens("3.25 1e-9 2.5E+3 7e2 1. x 1.y 4e 1e999");
+    assert_eq!(l.next(), Some(Token::Float(3.25)));
+    assert_eq!(l.next(), Some(Token::Float(1e-9)));
+    assert_eq!(l.next(), Some(Token::Float(2500.0)));
+    assert_eq!(l.next(), Some(Token::Float(700.0)));
+    assert_eq!(l.next(), Some(Token::Int(1)));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("x"))));
+    assert_eq!(l.next(), Some(Token::Int(1)));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("y"))));
+    assert_eq!(l.next(), Some(Token::Int(4)));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("e"))));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), None);
+  }
+
+  #[test]
+  fn test_comments() {
+    let mut l = tokens("
+      // a line comment
+      let x = 4 / 2; // trailing
+      /* a block
+         comment */ x /**/ /* ** / */
+    ");
+    assert_eq!(l.next(), Some(Token::Let));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("x"))));
+    assert_eq!(l.next(), Some(Token::Assign));
+    assert_eq!(l.next(), Some(Token::Int(4)));
+    assert_eq!(l.next(), Some(Token::Slash));
+    assert_eq!(l.next(), Some(Token::Int(2)));
+    assert_eq!(l.next(), Some(Token::Semicolon));
+    assert_eq!(l.next(), Some(Token::Ident(String::from("x"))));
+    assert_eq!(l.next(), None);
+
+    // `~/` is floor division, not the start of a comment
+    let mut l = tokens("7 ~/ 2 ~//2");
+    assert_eq!(l.next(), Some(Token::Int(7)));
+    assert_eq!(l.next(), Some(Token::TildeSlash));
+    assert_eq!(l.next(), Some(Token::Int(2)));
+    assert_eq!(l.next(), Some(Token::TildeSlash));
+    assert_eq!(l.next(), Some(Token::Slash));
+    assert_eq!(l.next(), Some(Token::Int(2)));
+    assert_eq!(l.next(), None);
+
+    let mut l = Lexer::new("1 /* never closed");
+    assert_eq!(l.next().map(|t| t.token), Some(Token::Int(1)));
+    assert_eq!(l.next(), Some(SpannedToken {
+      token: Token::Illegal,
+      span: Span { line: 1, column: 3, byte_offset: 2 },
+    }));
+    assert_eq!(l.next(), None);
+    assert_eq!(tokens("// only a comment").next(), None);
+  }
+
+  #[test]
+  fn test_spans() {
+    let spans: Vec<Span> = Lexer::new("let x\n  = \"é\";").map(|t| t.span).collect();
+    assert_eq!(spans, vec!(
+      Span { line: 1, column: 1, byte_offset: 0 },
+      Span { line: 1, column: 5, byte_offset: 4 },
+      Span { line: 2, column: 3, byte_offset: 8 },
+      Span { line: 2, column: 5, byte_offset: 10 },
+      Span { line: 2, column: 8, byte_offset: 14 },
+    ));
+  }
+
+  #[test]
+  fn test_diff_tokens() {
+    let old_src = "let x = 1 + y;";
+    let old_tokens: Vec<SpannedToken> = Lexer::new(old_src).collect();
+    let span = |column, byte_offset| Span { line: 1, column, byte_offset };
+
+    let diff = diff_tokens(old_src, "let x = 1 * 2 + y;", &old_tokens);
+    assert_eq!(diff, TokenDiff {
+      start: 4,
+      old_end: 4,
+      new_end: 6,
+      tokens: vec!(
+        SpannedToken { token: Token::Asterisk, span: span(11, 10) },
+        SpannedToken { token: Token::Int(2), span: span(13, 12) },
+      ),
+      moved: Some((span(11, 10), span(15, 14))),
+    });
+
+    let diff = diff_tokens(old_src, "let x = y;", &old_tokens);
+    assert_eq!(diff, TokenDiff {
+      start: 3,
+      old_end: 5,
+      new_end: 3,
+      tokens: vec!(),
+      moved: Some((span(13, 12), span(9, 8))),
+    });
+
+    let diff = diff_tokens(old_src, old_src, &old_tokens);
+    assert_eq!(diff, TokenDiff { start: 7, old_end: 7, new_end: 7, tokens: vec!(), moved: None });
+  }
+
+  #[test]
+  fn test_diff_tokens_matches_relexing() {
+    let edits = [
+      ("let x = 1 + y;", "let x = 1 + y;\nputs(x);"),
+      ("let x = 1 + y;\nputs(x);", "let x =\n  1 + y;\nputs(x);"),
+      ("let a = 1.x;", "let a = 1.5;"),
+      ("let a = 1e+x;", "let a = 1e+5;"),
+      ("let ab = [1, 2];", "let abc = [1, 2];"),
+      ("f(1); /* c */ g(2);\nh(3);", "f(1); /* c */ g(\"two\");\nh(3);"),
+      ("f(1);\n\nh(3);", "f(1);"),
+      ("\"a\" + b", "\"a + b"),
+      ("x", ""),
+      ("", "let é = 1;"),
+    ];
+    for (old_src, new_src) in edits.iter() {
+      let old_tokens: Vec<SpannedToken> = Lexer::new(old_src).collect();
+      let expected: Vec<SpannedToken> = Lexer::new(new_src).collect();
+      let diff = diff_tokens(old_src, new_src, &old_tokens);
+      assert_eq!(diff.apply(&old_tokens), expected, "{:?} -> {:?}", old_src, new_src);
+    }
+  }
+
+  #[test]
+  fn test_strings() {
+    let mut l = tokens(r#""foo bar" "a\"b\\c\nd" "" "bad\q" "unterminated"#);
+    assert_eq!(l.next(), Some(Token::Str(String::from("foo bar"))));
+    assert_eq!(l.next(), Some(Token::Str(String::from("a\"b\\c\nd"))));
+    assert_eq!(l.next(), Some(Token::Str(String::new())));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), Some(Token::Illegal));
+    assert_eq!(l.next(), None);
+  }
+
+  #[test]
+  fn test_lex_errors() {
+    let mut l = Lexer::new("\"a\\qb\\x\" 99999999999999999999 ~\n/* open");
+    while l.next().is_some() {}
+    let errors: Vec<String> = l.errors().iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec!(
+      "1:1: error: unknown escape sequence \\q",
+      "1:10: error: number 99999999999999999999 is out of range",
+      "1:31: error: unexpected character '~'",
+      "2:1: error: unterminated block comment",
+    ));
+  }
+
   // TODO: ought to test some more things, but we'll call this good for now.
 }
\ No newline at end of file
@@ -1 +1,22 @@
+pub mod ast;
+pub mod builtins;
+pub mod code;
+pub mod compiler;
+pub mod diagnostics;
+pub mod error;
+pub mod eval;
+pub mod fmt;
+pub mod interpreter;
+pub mod json;
 pub mod lexer;
+pub mod lint;
+pub mod macros;
+pub mod native;
+pub mod object;
+pub mod observer;
+pub mod optimize;
+pub mod parser;
+pub mod repl;
+pub mod session;
+pub mod support;
+pub mod vm;
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::ast::*;
+use crate::builtins;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+  UnusedComparison(InfixOperator),
+  UnusedPureCall(String),
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Warning::UnusedComparison(operator) =>
+        write!(f, "result of `{}` comparison is unused", operator.symbol()),
+      Warning::UnusedPureCall(name) =>
+        write!(f, "result of `{}` is unused; it has no other effect", name),
+    }
+  }
+}
+
+/// Finds expression statements whose value is thrown away although computing it can have had
+/// no effect. The last statement of a program or block is its value, so it is never flagged.
+pub fn check_unused_values(program: &Program) -> Vec<Warning> {
+  let mut checker = Checker { bound: HashSet::new(), warnings: vec!() };
+  collect_bindings(&program.statements, &mut checker.bound);
+  checker.check_statements(&program.statements);
+  checker.warnings
+}
+
+struct Checker {
+  // Any name bound anywhere in the program; a call through one of these may not be the builtin.
+  bound: HashSet<String>,
+  warnings: Vec<Warning>,
+}
+
+impl Checker {
+  fn check_statements(&mut self, statements: &[Statement]) {
+    for (i, statement) in statements.iter().enumerate() {
+      let expression = match statement {
+        Statement::Let(_, e) | Statement::Assign(_, e) | Statement::Return(e) => e,
+        Statement::Expression(e) => {
+          if i + 1 < statements.len() {
+            self.check_discarded(e);
+          }
+          e
+        },
+      };
+      self.check_expression(expression);
+    }
+  }
+
+  fn check_discarded(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Infix(operator, _, _) => match operator {
+        InfixOperator::Eq | InfixOperator::NotEq | InfixOperator::LT | InfixOperator::GT
+          | InfixOperator::LtEq | InfixOperator::GtEq =>
+          self.warnings.push(Warning::UnusedComparison(*operator)),
+        _ => {},
+      },
+      Expression::Call { function, .. } => {
+        if let Expression::Identifier(name) = function.as_ref() {
+          let pure = builtins::spec(name).is_some_and(|b| b.pure);
+          if pure && !self.bound.contains(name) {
+            self.warnings.push(Warning::UnusedPureCall(name.clone()));
+          }
+        }
+      },
+      _ => {},
+    }
+  }
+
+  // Looks for blocks nested anywhere inside the expression.
+  fn check_expression(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Identifier(_) | Expression::IntegerLiteral(_) | Expression::FloatLiteral(_)
+        | Expression::StringLiteral(_) | Expression::Boolean(_) => {},
+      Expression::ArrayLiteral(elements) => elements.iter().for_each(|e| self.check_expression(e)),
+      Expression::HashLiteral(pairs) => for (k, v) in pairs {
+        self.check_expression(k);
+        self.check_expression(v);
+      },
+      Expression::Prefix(_, right) => self.check_expression(right),
+      Expression::Infix(_, left, right) => {
+        self.check_expression(left);
+        self.check_expression(right);
+      },
+      Expression::If { condition, consequence, alternative } => {
+        self.check_expression(condition);
+        self.check_statements(&consequence.statements);
+        if let Some(alternative) = alternative {
+          self.check_statements(&alternative.statements);
+        }
+      },
+      Expression::While { condition, body } => {
+        self.check_expression(condition);
+        self.check_statements(&body.statements);
+        // a loop body's last statement is thrown away too
+        if let Some(Statement::Expression(e)) = body.statements.last() {
+          self.check_discarded(e);
+        }
+      },
+      Expression::FunctionLiteral { body, .. } | Expression::MacroLiteral { body, .. } =>
+        self.check_statements(&body.statements),
+      Expression::Call { function, arguments } => {
+        self.check_expression(function);
+        arguments.iter().for_each(|a| self.check_expression(a));
+      },
+      Expression::Index { left, index } => {
+        self.check_expression(left);
+        self.check_expression(index);
+      },
+      Expression::Slice { left, start, end } => {
+        self.check_expression(left);
+        start.iter().chain(end).for_each(|b| self.check_expression(b));
+      },
+    }
+  }
+}
+
+fn collect_bindings(statements: &[Statement], bound: &mut HashSet<String>) {
+  for statement in statements {
+    let expression = match statement {
+      Statement::Let(name, e) => {
+        bound.insert(name.clone());
+        e
+      },
+      Statement::Assign(_, e) | Statement::Return(e) | Statement::Expression(e) => e,
+    };
+    collect_expression_bindings(expression, bound);
+  }
+}
+
+fn collect_expression_bindings(expression: &Expression, bound: &mut HashSet<String>) {
+  match expression {
+    Expression::If { consequence, alternative, .. } => {
+      collect_bindings(&consequence.statements, bound);
+      if let Some(alternative) = alternative {
+        collect_bindings(&alternative.statements, bound);
+      }
+    },
+    Expression::While { body, .. } => collect_bindings(&body.statements, bound),
+    Expression::FunctionLiteral { name, parameters, body } => {
+      bound.extend(name.iter().chain(parameters).cloned());
+      collect_bindings(&body.statements, bound);
+    },
+    Expression::MacroLiteral { parameters, body } => {
+      bound.extend(parameters.iter().cloned());
+      collect_bindings(&body.statements, bound);
+    },
+    Expression::ArrayLiteral(elements) => elements.iter().for_each(|e| collect_expression_bindings(e, bound)),
+    Expression::HashLiteral(pairs) => for (k, v) in pairs {
+      collect_expression_bindings(k, bound);
+      collect_expression_bindings(v, bound);
+    },
+    Expression::Prefix(_, right) => collect_expression_bindings(right, bound),
+    Expression::Infix(_, left, right) | Expression::Index { left, index: right } => {
+      collect_expression_bindings(left, bound);
+      collect_expression_bindings(right, bound);
+    },
+    Expression::Slice { left, start, end } => {
+      collect_expression_bindings(left, bound);
+      start.iter().chain(end).for_each(|b| collect_expression_bindings(b, bound));
+    },
+    Expression::Call { function, arguments } => {
+      collect_expression_bindings(function, bound);
+      arguments.iter().for_each(|a| collect_expression_bindings(a, bound));
+    },
+    Expression::Identifier(_) | Expression::IntegerLiteral(_) | Expression::FloatLiteral(_)
+      | Expression::StringLiteral(_) | Expression::Boolean(_) => {},
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn warnings(input: &str) -> Vec<String> {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    check_unused_values(&program).iter().map(|w| w.to_string()).collect()
+  }
+
+  #[test]
+  fn test_unused_values() {
+    assert_eq!(warnings("let x = 1; x == 5; x"), vec!("result of `==` comparison is unused"));
+    assert_eq!(warnings("let a = [1]; len(a); push(a, 2); a"), vec!(
+      "result of `len` is unused; it has no other effect",
+      "result of `push` is unused; it has no other effect",
+    ));
+    assert_eq!(warnings("let f = fn(x) { x < 1; x }; f(1)"), vec!("result of `<` comparison is unused"));
+    assert_eq!(warnings("while (true) { 1 == 1 }"), vec!("result of `==` comparison is unused"));
+  }
+
+  #[test]
+  fn test_used_values_are_not_flagged() {
+    // last statements are values; puts is impure; shadowed builtins may be anything
+    assert!(warnings("let x = 1; x == 5").is_empty());
+    assert!(warnings("let f = fn(x) { x == 1 }; f(1)").is_empty());
+    assert!(warnings(r#"puts("hi"); 1"#).is_empty());
+    assert!(warnings("let len = fn(x) { puts(x) }; len(1); 2").is_empty());
+    assert!(warnings("let x = 1 + 2; x + 1; x").is_empty());
+  }
+}
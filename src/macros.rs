@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ast::{self, *};
+use crate::error::MonkeyError;
+use crate::eval::{self, eval};
+use crate::object::{Env, Environment, Object};
+
+/// Removes top-level `let name = macro(...) { ... };` statements from the program, binding the
+/// macros they define in `env`.
+pub fn define_macros(program: &mut Program, env: &Env) {
+  program.statements.retain(|statement| match statement {
+    Statement::Let(name, Expression::MacroLiteral { parameters, body }) => {
+      let definition = Object::Macro {
+        parameters: parameters.clone(),
+        body: body.clone(),
+        env: Rc::clone(env),
+      };
+      env.borrow_mut().set(name, definition);
+      false
+    },
+    _ => true,
+  });
+}
+
+/// Whether `define_macros` takes the statement out of the program.
+pub(crate) fn is_macro_definition(statement: &Statement) -> bool {
+  matches!(statement, Statement::Let(_, Expression::MacroLiteral { .. }))
+}
+
+/// Replaces each call to a macro defined in `env` with the code it returns. The macro's body
+/// runs with each parameter bound to the quoted, unevaluated argument, and must return a quote.
+///
+/// Names bound by `let` or as parameters inside a quote in the macro's body are renamed for
+/// each expansion, along with the references to them within their scope, so they can't capture
+/// or shadow names in the arguments spliced in beside them. A macro that means to bind a name for
+/// its arguments to use can build its code with `unhygienic_quote` instead. `gensym("prefix")`
+/// makes a fresh identifier in a macro's body.
+pub fn expand_macros(program: Program, env: &Env) -> Result<Program, MonkeyError> {
+  ast::modify_program(program, &mut |expression| match expression {
+    Expression::Call { function, arguments } => match definition(&function, env) {
+      Some(definition) => expand(&function, definition, arguments),
+      None => Ok(Expression::Call { function, arguments }),
+    },
+    expression => Ok(expression),
+  })
+}
+
+fn definition(function: &Expression, env: &Env) -> Option<Object> {
+  match function {
+    Expression::Identifier(name) => {
+      env.borrow().get(name).filter(|o| matches!(o, Object::Macro { .. }))
+    },
+    _ => None,
+  }
+}
+
+fn expand(
+  function: &Expression, definition: Object, arguments: Vec<Expression>,
+) -> Result<Expression, MonkeyError> {
+  let (parameters, body, env) = match definition {
+    Object::Macro { parameters, body, env } => (parameters, body, env),
+    _ => unreachable!("expanding a call to a non-macro"),
+  };
+  if parameters.len() != arguments.len() {
+    return Err(MonkeyError::runtime(format!(
+      "wrong number of arguments: want={}, got={}", parameters.len(), arguments.len()
+    )));
+  }
+  let inner = Environment::new_enclosed(&env);
+  inner.borrow_mut().register_builtin("gensym", gensym_builtin);
+  for (name, argument) in parameters.iter().zip(arguments) {
+    inner.borrow_mut().set(name, Object::Quote(argument));
+  }
+  let body = ast::modify_program(Program { statements: body.statements }, &mut hygienic_quote)?;
+  match eval(&body, &inner)? {
+    Object::Quote(expression) => Ok(expression),
+    other => Err(MonkeyError::runtime(format!(
+      "macro `{}` must return a QUOTE, got {}", function, other.type_name()
+    ))),
+  }
+}
+
+// Renames the bindings in the templates of `quote` calls, and turns `unhygienic_quote` calls into
+// plain quotes.
+fn hygienic_quote(expression: Expression) -> Result<Expression, MonkeyError> {
+  let called = |name: &str, function: &Expression, arguments: &[Expression]| {
+    matches!(function, Expression::Identifier(f) if f == name) && arguments.len() == 1
+  };
+  match expression {
+    Expression::Call { function, mut arguments } if called("quote", &function, &arguments) => {
+      rename_bindings(&mut arguments[0]);
+      Ok(Expression::Call { function, arguments })
+    },
+    Expression::Call { function, arguments } if called("unhygienic_quote", &function, &arguments) => {
+      let function = Box::new(Expression::Identifier(String::from("quote")));
+      Ok(Expression::Call { function, arguments })
+    },
+    expression => Ok(expression),
+  }
+}
+
+fn rename_bindings(template: &mut Expression) {
+  let mut renamer = Renamer { scopes: vec!(HashMap::new()) };
+  renamer.expression(template);
+}
+
+// Renames names bound in a template, and references to them from where the binding is in scope,
+// leaving free names alone. As in the evaluator, a function has a scope of its own and a block
+// doesn't, and a `let` binds from the next statement on, except that a function can refer to
+// the name it's being bound to. Nothing inside `unquote(...)` is part of the template.
+struct Renamer {
+  // Fresh names for the names bound so far in each enclosing scope, innermost last.
+  scopes: Vec<HashMap<String, String>>,
+}
+
+impl Renamer {
+  fn bind(&mut self, name: &mut String) {
+    let fresh = gensym(name);
+    let scope = self.scopes.last_mut().expect("renaming without a scope");
+    scope.insert(mem::replace(name, fresh.clone()), fresh);
+  }
+
+  fn reference(&self, name: &mut String) {
+    if let Some(fresh) = self.scopes.iter().rev().find_map(|scope| scope.get(name.as_str())) {
+      *name = fresh.clone();
+    }
+  }
+
+  fn function(
+    &mut self, name: Option<&mut String>, parameters: &mut [String], body: &mut BlockStatement,
+  ) {
+    self.scopes.push(HashMap::new());
+    name.into_iter().chain(parameters).for_each(|n| self.bind(n));
+    self.statements(&mut body.statements);
+    self.scopes.pop();
+  }
+
+  fn statements(&mut self, statements: &mut [Statement]) {
+    for statement in statements {
+      match statement {
+        Statement::Let(name, value @ Expression::FunctionLiteral { .. }) => {
+          self.bind(name);
+          self.expression(value);
+        },
+        Statement::Let(name, value) => {
+          self.expression(value);
+          self.bind(name);
+        },
+        Statement::Assign(name, value) => {
+          self.expression(value);
+          self.reference(name);
+        },
+        Statement::Return(value) | Statement::Expression(value) => self.expression(value),
+      }
+    }
+  }
+
+  fn expression(&mut self, expression: &mut Expression) {
+    match expression {
+      Expression::Identifier(name) => self.reference(name),
+      Expression::IntegerLiteral(_) | Expression::FloatLiteral(_) | Expression::StringLiteral(_)
+        | Expression::Boolean(_) => {},
+      Expression::ArrayLiteral(elements) => elements.iter_mut().for_each(|e| self.expression(e)),
+      Expression::HashLiteral(pairs) => for (k, v) in pairs {
+        self.expression(k);
+        self.expression(v);
+      },
+      Expression::Prefix(_, right) => self.expression(right),
+      Expression::Infix(_, left, right) | Expression::Index { left, index: right } => {
+        self.expression(left);
+        self.expression(right);
+      },
+      Expression::If { condition, consequence, alternative } => {
+        self.expression(condition);
+        self.statements(&mut consequence.statements);
+        if let Some(alternative) = alternative {
+          self.statements(&mut alternative.statements);
+        }
+      },
+      Expression::While { condition, body } => {
+        self.expression(condition);
+        self.statements(&mut body.statements);
+      },
+      Expression::FunctionLiteral { name, parameters, body } =>
+        self.function(name.as_mut(), parameters, body),
+      Expression::MacroLiteral { parameters, body } => self.function(None, parameters, body),
+      Expression::Slice { left, start, end } => {
+        self.expression(left);
+        start.iter_mut().chain(end).for_each(|b| self.expression(b));
+      },
+      Expression::Call { function, arguments } => {
+        if eval::is_unquote(function, arguments) {
+          return;
+        }
+        self.expression(function);
+        arguments.iter_mut().for_each(|a| self.expression(a));
+      },
+    }
+  }
+}
+
+static GENSYM_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A name starting with `prefix` that no other call returns. Identifiers can't contain digits, so
+/// the count is spelled in letters to keep the name one that could be written in code.
+pub fn gensym(prefix: &str) -> String {
+  let mut n = GENSYM_COUNT.fetch_add(1, Ordering::Relaxed);
+  let mut letters = vec!();
+  loop {
+    letters.push(b'a' + (n % 26) as u8);
+    n /= 26;
+    if n == 0 {
+      break;
+    }
+  }
+  letters.reverse();
+  format!("{}__{}", prefix, String::from_utf8(letters).unwrap())
+}
+
+fn gensym_builtin(args: Vec<Object>) -> Object {
+  match args.as_slice() {
+    [Object::Str(prefix)] => Object::Quote(Expression::Identifier(gensym(prefix))),
+    [other] => Object::Error(format!("argument to `gensym` must be STRING, got {}", other.type_name())),
+    _ => Object::Error(format!("wrong number of arguments. got={}, want=1", args.len())),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn parse(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap()
+  }
+
+  fn expanded(input: &str) -> Result<Program, MonkeyError> {
+    let env = Environment::new();
+    let mut program = parse(input);
+    define_macros(&mut program, &env);
+    expand_macros(program, &env)
+  }
+
+  #[test]
+  fn test_define_macros() {
+    let env = Environment::new();
+    let mut program = parse("let number = 1; let f = fn(x) { x }; let m = macro(x, y) { x + y; };");
+    define_macros(&mut program, &env);
+    assert_eq!(program.statements.len(), 2);
+    assert!(env.borrow().get("number").is_none());
+    assert!(env.borrow().get("f").is_none());
+    let definition = env.borrow().get("m");
+    match definition {
+      Some(Object::Macro { parameters, body, .. }) => {
+        assert_eq!(parameters, vec!(String::from("x"), String::from("y")));
+        assert_eq!(body.to_string(), "{ (x + y) }");
+      },
+      other => panic!("m is {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_expand_macros() {
+    let cases = [
+      ("let infix = macro() { quote(1 + 2); }; infix();", "(1 + 2)"),
+      ("let rev = macro(a, b) { quote(unquote(b) - unquote(a)) }; rev(2 + 2, 10 - 5)", "(10 - 5) - (2 + 2)"),
+      (r#"
+        let unless = macro(condition, consequence, alternative) {
+          quote(if (!(unquote(condition))) { unquote(consequence); } else { unquote(alternative); });
+        };
+        unless(10 > 5, puts("not greater"), puts("greater"));
+      "#, r#"if (!(10 > 5)) { puts("not greater") } else { puts("greater") }"#),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(expanded(input), Ok(parse(expected)), "input {:?}", input);
+    }
+  }
+
+  fn run(input: &str) -> Object {
+    match expanded(input).and_then(|program| eval(&program, &Environment::new())) {
+      Ok(value) => value,
+      Err(MonkeyError::Runtime { message, .. }) => Object::Error(message),
+      Err(e) => panic!("running {:?} failed with {}", input, e),
+    }
+  }
+
+  #[test]
+  fn test_hygiene() {
+    let or = "let or = macro(a, b) {
+      quote(fn() { let v = unquote(a); if (v) { v } else { unquote(b) } }())
+    };";
+    assert_eq!(run(&format!("{} let v = 7; or(false, v)", or)), Object::Integer(7));
+    let shadowing = format!("{} let f = fn(v) {{ or(v, 2) }}; [f(1), f(false)]", or);
+    assert_eq!(run(&shadowing).to_string(), "[1, 2]");
+
+    let program = expanded(&format!("{} or(1, 2)", or)).unwrap();
+    let expansion = program.to_string();
+    assert!(expansion.starts_with("fn() { let v__"), "expansion {}", expansion);
+    assert!(!expansion.contains(" v "), "expansion {}", expansion);
+
+    // parameters are renamed too, while free names such as builtins are left alone
+    let apply = "let apply = macro(e) { quote(fn(x) { len(x) + unquote(e) }([1])) };
+      let x = 10; apply(x)";
+    assert_eq!(run(apply), Object::Integer(11));
+
+    // a binding renames only the references in its scope, not the same name outside it
+    let shadow = "let m = macro() { quote([fn(len) { len }(1), len([1, 2])]) }; m()";
+    assert_eq!(run(shadow).to_string(), "[1, 2]");
+    let program = expanded(shadow).unwrap().to_string();
+    let renamed_parameter = program.starts_with("[fn(len__") && program.ends_with(", len([1, 2])]");
+    assert!(renamed_parameter, "expansion {}", program);
+    let sequence = "let m = macro() {
+      quote(fn(n) { let total = len(n); let len = 2; total + len }([1]))
+    }; m()";
+    assert_eq!(run(sequence), Object::Integer(3));
+    let recursive = "let m = macro() {
+      quote(fn() { let count = fn(n) { if (n == 0) { 0 } else { 1 + count(n - 1) } }; count(4) }())
+    }; m()";
+    assert_eq!(run(recursive), Object::Integer(4));
+
+    // unhygienic_quote lets a macro bind a name on purpose
+    let aif = "let aif = macro(c, t) {
+      unhygienic_quote(fn() { let it = unquote(c); if (it) { unquote(t) } }())
+    };";
+    assert_eq!(run(&format!("{} aif(5, it * 2)", aif)), Object::Integer(10));
+  }
+
+  #[test]
+  fn test_gensym() {
+    let a = gensym("tmp");
+    let b = gensym("tmp");
+    assert!(a.starts_with("tmp__") && b.starts_with("tmp__") && a != b);
+    assert!(a.chars().all(|c| c.is_ascii_alphabetic() || c == '_'));
+
+    let program = expanded(r#"let m = macro() { let g = gensym("x"); quote(unquote(g) + 1) }; m()"#);
+    match program.unwrap().statements.as_slice() {
+      [Statement::Expression(Expression::Infix(_, left, _))] =>
+        assert!(left.to_string().starts_with("x__")),
+      other => panic!("expanded to {:?}", other),
+    }
+    assert_eq!(run("let m = macro() { quote(unquote(gensym(1))) }; m()"), Object::Error(String::from(
+      "argument to `gensym` must be STRING, got INTEGER"
+    )));
+  }
+
+  #[test]
+  fn test_expansion_errors() {
+    let cases = [
+      ("let m = macro(x) { 1 }; m(2)", "macro `m` must return a QUOTE, got INTEGER"),
+      ("let m = macro(x) { quote(x) }; m()", "wrong number of arguments: want=1, got=0"),
+      ("let m = macro() { quote(unquote(y)) }; m()", "identifier not found: y"),
+    ];
+    for (input, expected) in cases.iter() {
+      let expected = MonkeyError::runtime(String::from(*expected));
+      assert_eq!(expanded(input), Err(expected), "input {:?}", input);
+    }
+  }
+}
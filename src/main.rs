@@ -0,0 +1,140 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use monkey_interpreter::compiler::CompilerOptions;
+use monkey_interpreter::diagnostics;
+use monkey_interpreter::repl;
+use monkey_interpreter::session::{self, Division, Engine, Options};
+
+const USAGE: &str = "\
+usage: monkey [--engine=eval|vm] [-O0|-O1|-O2] [--division=truncate|floor]
+              [run [--fail-fast] <file or directory>...]
+       monkey expand <file>
+       monkey fmt <file>
+       monkey explain <code>";
+
+fn main() -> io::Result<()> {
+  let mut options = Options::new(Engine::Eval);
+  let mut fail_fast = false;
+  let mut args = vec!();
+  for arg in env::args().skip(1) {
+    if arg == "--fail-fast" {
+      fail_fast = true;
+      continue;
+    }
+    if let Some(level) = arg.strip_prefix("-O") {
+      match level.parse() {
+        Ok(level @ 0..=2) => options.compiler = CompilerOptions::level(level),
+        _ => usage_error(&format!("unknown optimization level {}; expected 0, 1 or 2", level)),
+      }
+      continue;
+    }
+    if let Some(division) = arg.strip_prefix("--division=") {
+      options.division = match division {
+        "truncate" => Division::Truncate,
+        "floor" => Division::Floor,
+        other => usage_error(&format!("unknown division {}; expected truncate or floor", other)),
+      };
+      continue;
+    }
+    match arg.strip_prefix("--engine=") {
+      Some("eval") => options.engine = Engine::Eval,
+      Some("vm") => options.engine = Engine::Vm,
+      Some(other) => usage_error(&format!("unknown engine {}; expected eval or vm", other)),
+      None => args.push(arg),
+    }
+  }
+
+  match args.as_slice() {
+    [] => {
+      let stdin = io::stdin();
+      repl::start(stdin.lock(), io::stdout(), options)
+    },
+    [command, paths @ ..] if command == "run" && !paths.is_empty() => {
+      let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+      if !run_scripts(&paths, options, fail_fast)? {
+        process::exit(1);
+      }
+      Ok(())
+    },
+    [command, path] if command == "expand" => print_script(path, session::expand_script),
+    [command, path] if command == "fmt" => print_script(path, session::format_script),
+    [command, code] if command == "explain" => match diagnostics::explain(code) {
+      Some(explanation) => {
+        println!("{}", explanation);
+        Ok(())
+      },
+      None => {
+        eprintln!("no explanation for error code {}", code);
+        process::exit(1);
+      },
+    },
+    _ => usage_error(USAGE),
+  }
+}
+
+type Printer = fn(&str, &str, io::Stdout, io::Stderr) -> io::Result<bool>;
+
+// Prints a script transformed by `print`, exiting with an error if it fails.
+fn print_script(path: &str, print: Printer) -> io::Result<()> {
+  let printed = match fs::read_to_string(path) {
+    Ok(source) => print(path, &source, io::stdout(), io::stderr())?,
+    Err(e) => {
+      eprintln!("{}: {}", path, e);
+      false
+    },
+  };
+  if !printed {
+    process::exit(1);
+  }
+  Ok(())
+}
+
+// Runs each script in its own session. With more than one, a summary follows their output.
+fn run_scripts(paths: &[PathBuf], options: Options, fail_fast: bool) -> io::Result<bool> {
+  let scripts = session::collect_scripts(paths)?;
+  let mut failed = vec!();
+  let mut run = 0;
+  for script in &scripts {
+    run += 1;
+    if !run_script(script, options)? {
+      failed.push(script);
+      if fail_fast {
+        break;
+      }
+    }
+  }
+
+  if scripts.len() > 1 {
+    eprintln!();
+    for script in &failed {
+      eprintln!("FAILED {}", script.display());
+    }
+    let skipped = scripts.len() - run;
+    eprint!("{} passed, {} failed", run - failed.len(), failed.len());
+    if skipped > 0 {
+      eprint!(", {} not run", skipped);
+    }
+    eprintln!();
+  }
+  Ok(failed.is_empty())
+}
+
+fn run_script(path: &Path, options: Options) -> io::Result<bool> {
+  let name = path.display().to_string();
+  match fs::read_to_string(path) {
+    Ok(source) => session::run_script(&name, &source, options, io::stderr()),
+    Err(e) => {
+      eprintln!("{}: {}", name, e);
+      Ok(false)
+    },
+  }
+}
+
+fn usage_error(message: &str) -> ! {
+  eprintln!("{}", message);
+  process::exit(2);
+}
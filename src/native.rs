@@ -0,0 +1,163 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::builtins::check_arity;
+use crate::error::MonkeyError;
+use crate::object::{BuiltinFunction, Object};
+
+/// The implementation of a builtin: one of the standard builtins, or a closure from the program
+/// embedding Monkey.
+#[derive(Clone)]
+pub struct NativeFunction {
+  function: Rc<dyn Fn(Vec<Object>) -> Object>,
+  arity: Option<usize>,
+}
+
+impl NativeFunction {
+  fn new<F: Fn(Vec<Object>) -> Object + 'static>(arity: Option<usize>, function: F) -> Self {
+    NativeFunction { function: Rc::new(function), arity }
+  }
+
+  pub fn call(&self, args: Vec<Object>) -> Object {
+    (self.function)(args)
+  }
+
+  /// How many arguments the function takes, or `None` if it takes any number or doesn't say.
+  pub fn arity(&self) -> Option<usize> {
+    self.arity
+  }
+
+  pub fn accepts(&self, arity: usize) -> bool {
+    self.arity.is_none_or(|a| a == arity)
+  }
+}
+
+// A bare function checks its own arguments, so nothing is known about its arity.
+impl From<BuiltinFunction> for NativeFunction {
+  fn from(function: BuiltinFunction) -> Self {
+    NativeFunction::new(None, function)
+  }
+}
+
+impl fmt::Debug for NativeFunction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "NativeFunction")
+  }
+}
+
+/// Takes the arguments of a native function as they are, however many there are.
+pub struct Variadic;
+
+/// A Rust function that can be called from Monkey; see `Environment::register_fn`. `Args`
+/// only tells the implementations apart.
+///
+/// A function of `&[Object]` takes any number of arguments. A function of up to three typed
+/// arguments is called with exactly that many, each converted with `TryFrom<Object>`, so a
+/// wrong count or type is an error in the script rather than in the host.
+pub trait NativeFn<Args> {
+  fn into_native(self, name: &str) -> NativeFunction;
+}
+
+impl<F> NativeFn<Variadic> for F
+where F: Fn(&[Object]) -> Result<Object, MonkeyError> + 'static {
+  fn into_native(self, _: &str) -> NativeFunction {
+    NativeFunction::new(None, move |args: Vec<Object>| self(&args).unwrap_or_else(error))
+  }
+}
+
+// Scripts see only the message of a runtime error, as they do for errors they raise themselves.
+fn error(e: MonkeyError) -> Object {
+  match e {
+    MonkeyError::Runtime { message, .. } => Object::Error(message),
+    other => Object::Error(other.to_string()),
+  }
+}
+
+fn argument<T>(name: &str, position: usize, arg: Object) -> Result<T, Object>
+where T: TryFrom<Object>, T::Error: Into<MonkeyError> {
+  T::try_from(arg).map_err(|e| match e.into() {
+    MonkeyError::Runtime { message, .. } =>
+      Object::Error(format!("argument {} to `{}`: {}", position, name, message)),
+    other => error(other),
+  })
+}
+
+impl<F, R> NativeFn<()> for F
+where F: Fn() -> Result<R, MonkeyError> + 'static, R: Into<Object> {
+  fn into_native(self, _: &str) -> NativeFunction {
+    NativeFunction::new(Some(0), move |args: Vec<Object>| {
+      let call = || {
+        check_arity(&args, 0)?;
+        self().map_err(error)
+      };
+      call().map_or_else(|e| e, Into::into)
+    })
+  }
+}
+
+impl<F, A, R> NativeFn<(A,)> for F
+where
+  F: Fn(A) -> Result<R, MonkeyError> + 'static,
+  A: TryFrom<Object>, A::Error: Into<MonkeyError>,
+  R: Into<Object>,
+{
+  fn into_native(self, name: &str) -> NativeFunction {
+    let name = String::from(name);
+    NativeFunction::new(Some(1), move |args: Vec<Object>| {
+      let call = || {
+        check_arity(&args, 1)?;
+        let mut args = args.into_iter();
+        let a = argument(&name, 1, args.next().unwrap())?;
+        self(a).map_err(error)
+      };
+      call().map_or_else(|e| e, Into::into)
+    })
+  }
+}
+
+impl<F, A, B, R> NativeFn<(A, B)> for F
+where
+  F: Fn(A, B) -> Result<R, MonkeyError> + 'static,
+  A: TryFrom<Object>, A::Error: Into<MonkeyError>,
+  B: TryFrom<Object>, B::Error: Into<MonkeyError>,
+  R: Into<Object>,
+{
+  fn into_native(self, name: &str) -> NativeFunction {
+    let name = String::from(name);
+    NativeFunction::new(Some(2), move |args: Vec<Object>| {
+      let call = || {
+        check_arity(&args, 2)?;
+        let mut args = args.into_iter();
+        let a = argument(&name, 1, args.next().unwrap())?;
+        let b = argument(&name, 2, args.next().unwrap())?;
+        self(a, b).map_err(error)
+      };
+      call().map_or_else(|e| e, Into::into)
+    })
+  }
+}
+
+impl<F, A, B, C, R> NativeFn<(A, B, C)> for F
+where
+  F: Fn(A, B, C) -> Result<R, MonkeyError> + 'static,
+  A: TryFrom<Object>, A::Error: Into<MonkeyError>,
+  B: TryFrom<Object>, B::Error: Into<MonkeyError>,
+  C: TryFrom<Object>, C::Error: Into<MonkeyError>,
+  R: Into<Object>,
+{
+  fn into_native(self, name: &str) -> NativeFunction {
+    let name = String::from(name);
+    NativeFunction::new(Some(3), move |args: Vec<Object>| {
+      let call = || {
+        check_arity(&args, 3)?;
+        let mut args = args.into_iter();
+        let a = argument(&name, 1, args.next().unwrap())?;
+        let b = argument(&name, 2, args.next().unwrap())?;
+        let c = argument(&name, 3, args.next().unwrap())?;
+        self(a, b, c).map_err(error)
+      };
+      call().map_or_else(|e| e, Into::into)
+    })
+  }
+}
@@ -0,0 +1,400 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{BlockStatement, Expression};
+use crate::code::Instructions;
+use crate::error::MonkeyError;
+use crate::native::{NativeFn, NativeFunction};
+
+#[derive(Debug, Clone)]
+pub enum Object {
+  Integer(i64),
+  Float(f64),
+  Boolean(bool),
+  Str(String),
+  Array(Vec<Object>),
+  Hash(HashMap<HashKey, HashPair>),
+  Null,
+  Function {
+    parameters: Vec<String>,
+    body: BlockStatement,
+    env: Env,
+  },
+  Builtin {
+    name: String,
+    function: NativeFunction,
+  },
+  /// Unevaluated code, as returned by `quote` and passed to macros.
+  Quote(Expression),
+  Macro {
+    parameters: Vec<String>,
+    body: BlockStatement,
+    env: Env,
+  },
+  CompiledFunction(Rc<CompiledFunction>),
+  Closure(Rc<Closure>),
+  ReturnValue(Box<Object>),
+  Error(String),
+}
+
+pub type BuiltinFunction = fn(Vec<Object>) -> Object;
+
+/// A function body lowered to bytecode. These only live in the constants pool; the VM wraps
+/// them in a `Closure` when the function literal is evaluated.
+#[derive(Debug, PartialEq)]
+pub struct CompiledFunction {
+  pub instructions: Instructions,
+  pub num_locals: usize,
+  pub num_parameters: usize,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+  pub function: Rc<CompiledFunction>,
+  pub free: Vec<Object>,
+}
+
+impl Object {
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Object::Integer(_) => "INTEGER",
+      Object::Float(_) => "FLOAT",
+      Object::Boolean(_) => "BOOLEAN",
+      Object::Str(_) => "STRING",
+      Object::Array(_) => "ARRAY",
+      Object::Hash(_) => "HASH",
+      Object::Null => "NULL",
+      Object::Function { .. } => "FUNCTION",
+      Object::Builtin { .. } => "BUILTIN",
+      Object::Quote(_) => "QUOTE",
+      Object::Macro { .. } => "MACRO",
+      Object::CompiledFunction(_) => "COMPILED_FUNCTION",
+      // to Monkey code a closure is just a function, whichever engine runs it
+      Object::Closure(_) => "FUNCTION",
+      Object::ReturnValue(_) => "RETURN_VALUE",
+      Object::Error(_) => "ERROR",
+    }
+  }
+
+  /// The value of a literal expression, if it is one.
+  pub fn from_literal(expression: &Expression) -> Option<Object> {
+    match expression {
+      Expression::IntegerLiteral(i) => Some(Object::Integer(*i)),
+      Expression::FloatLiteral(x) => Some(Object::Float(*x)),
+      Expression::StringLiteral(s) => Some(Object::Str(s.clone())),
+      Expression::Boolean(b) => Some(Object::Boolean(*b)),
+      _ => None,
+    }
+  }
+
+  /// The literal expression that evaluates to this object, for the types that have one.
+  pub fn into_literal(self) -> Option<Expression> {
+    match self {
+      Object::Integer(i) => Some(Expression::IntegerLiteral(i)),
+      Object::Float(x) => Some(Expression::FloatLiteral(x)),
+      Object::Str(s) => Some(Expression::StringLiteral(s)),
+      Object::Boolean(b) => Some(Expression::Boolean(b)),
+      _ => None,
+    }
+  }
+
+  /// A builtin named `name` that calls a Rust function; see `NativeFn`.
+  pub fn native<Args, F: NativeFn<Args>>(name: &str, function: F) -> Object {
+    Object::Builtin { name: String::from(name), function: function.into_native(name) }
+  }
+
+  pub fn is_error(&self) -> bool {
+    matches!(self, Object::Error(_))
+  }
+
+  /// Returns the key this object is stored under in a hash, or `None` if it can't be a key.
+  pub fn hash_key(&self) -> Option<HashKey> {
+    match self {
+      Object::Integer(i) => Some(HashKey::Integer(*i)),
+      Object::Boolean(b) => Some(HashKey::Boolean(*b)),
+      Object::Str(s) => Some(HashKey::Str(s.clone())),
+      _ => None,
+    }
+  }
+}
+
+// Conversions for programs embedding Monkey. Rust values convert to objects infallibly; objects
+// convert back only when they have the expected type, and `None` and `()` are null.
+
+impl From<i64> for Object {
+  fn from(i: i64) -> Self {
+    Object::Integer(i)
+  }
+}
+
+impl From<f64> for Object {
+  fn from(x: f64) -> Self {
+    Object::Float(x)
+  }
+}
+
+impl From<bool> for Object {
+  fn from(b: bool) -> Self {
+    Object::Boolean(b)
+  }
+}
+
+impl From<&str> for Object {
+  fn from(s: &str) -> Self {
+    Object::Str(String::from(s))
+  }
+}
+
+impl From<String> for Object {
+  fn from(s: String) -> Self {
+    Object::Str(s)
+  }
+}
+
+impl From<()> for Object {
+  fn from(_: ()) -> Self {
+    Object::Null
+  }
+}
+
+impl<T: Into<Object>> From<Vec<T>> for Object {
+  fn from(elements: Vec<T>) -> Self {
+    Object::Array(elements.into_iter().map(Into::into).collect())
+  }
+}
+
+impl<T: Into<Object>> From<Option<T>> for Object {
+  fn from(value: Option<T>) -> Self {
+    value.map_or(Object::Null, Into::into)
+  }
+}
+
+fn conversion_error(expected: &str, got: &Object) -> MonkeyError {
+  MonkeyError::runtime(format!("expected {}, got {}", expected, got.type_name()))
+}
+
+impl TryFrom<Object> for i64 {
+  type Error = MonkeyError;
+
+  fn try_from(object: Object) -> Result<Self, MonkeyError> {
+    match object {
+      Object::Integer(i) => Ok(i),
+      other => Err(conversion_error("INTEGER", &other)),
+    }
+  }
+}
+
+impl TryFrom<Object> for f64 {
+  type Error = MonkeyError;
+
+  fn try_from(object: Object) -> Result<Self, MonkeyError> {
+    match object {
+      Object::Float(x) => Ok(x),
+      other => Err(conversion_error("FLOAT", &other)),
+    }
+  }
+}
+
+impl TryFrom<Object> for bool {
+  type Error = MonkeyError;
+
+  fn try_from(object: Object) -> Result<Self, MonkeyError> {
+    match object {
+      Object::Boolean(b) => Ok(b),
+      other => Err(conversion_error("BOOLEAN", &other)),
+    }
+  }
+}
+
+impl TryFrom<Object> for String {
+  type Error = MonkeyError;
+
+  fn try_from(object: Object) -> Result<Self, MonkeyError> {
+    match object {
+      Object::Str(s) => Ok(s),
+      other => Err(conversion_error("STRING", &other)),
+    }
+  }
+}
+
+impl<T: TryFrom<Object, Error = MonkeyError>> TryFrom<Object> for Vec<T> {
+  type Error = MonkeyError;
+
+  fn try_from(object: Object) -> Result<Self, MonkeyError> {
+    match object {
+      Object::Array(elements) => elements.into_iter().map(T::try_from).collect(),
+      other => Err(conversion_error("ARRAY", &other)),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone)]
+pub enum HashKey {
+  Integer(i64),
+  Boolean(bool),
+  Str(String),
+}
+
+/// Hashes keep the original key object alongside the value so they can be displayed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HashPair {
+  pub key: Object,
+  pub value: Object,
+}
+
+// Functions hold their defining environment, which may in turn hold the function itself, so
+// functions compare by identity of that environment rather than structurally. Builtins compare
+// by name, and closures by identity.
+impl PartialEq for Object {
+  fn eq(&self, other: &Object) -> bool {
+    match (self, other) {
+      (Object::Integer(a), Object::Integer(b)) => a == b,
+      (Object::Float(a), Object::Float(b)) => a == b,
+      (Object::Boolean(a), Object::Boolean(b)) => a == b,
+      (Object::Str(a), Object::Str(b)) => a == b,
+      (Object::Array(a), Object::Array(b)) => a == b,
+      (Object::Hash(a), Object::Hash(b)) => a == b,
+      (Object::Null, Object::Null) => true,
+      (
+        Object::Function { parameters: p1, body: b1, env: e1 },
+        Object::Function { parameters: p2, body: b2, env: e2 },
+      ) => p1 == p2 && b1 == b2 && Rc::ptr_eq(e1, e2),
+      (Object::Builtin { name: a, .. }, Object::Builtin { name: b, .. }) => a == b,
+      (Object::Quote(a), Object::Quote(b)) => a == b,
+      (
+        Object::Macro { parameters: p1, body: b1, env: e1 },
+        Object::Macro { parameters: p2, body: b2, env: e2 },
+      ) => p1 == p2 && b1 == b2 && Rc::ptr_eq(e1, e2),
+      (Object::CompiledFunction(a), Object::CompiledFunction(b)) => a == b,
+      (Object::Closure(a), Object::Closure(b)) => Rc::ptr_eq(a, b),
+      (Object::ReturnValue(a), Object::ReturnValue(b)) => a == b,
+      (Object::Error(a), Object::Error(b)) => a == b,
+      _ => false,
+    }
+  }
+}
+
+impl fmt::Display for Object {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Object::Integer(i) => write!(f, "{}", i),
+      // debug formatting keeps the `.0` on whole numbers
+      Object::Float(x) => write!(f, "{:?}", x),
+      Object::Boolean(b) => write!(f, "{}", b),
+      Object::Str(s) => write!(f, "{}", s),
+      Object::Array(elements) => {
+        let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+        write!(f, "[{}]", elements.join(", "))
+      },
+      Object::Hash(pairs) => {
+        // sorted so that output doesn't depend on HashMap iteration order
+        let mut keys: Vec<&HashKey> = pairs.keys().collect();
+        keys.sort();
+        let pairs: Vec<String> = keys.iter()
+          .map(|k| format!("{}: {}", pairs[k].key, pairs[k].value))
+          .collect();
+        write!(f, "{{{}}}", pairs.join(", "))
+      },
+      Object::Null => write!(f, "null"),
+      Object::Function { parameters, .. } => write!(f, "fn({}) {{...}}", parameters.join(", ")),
+      Object::Builtin { name, .. } => write!(f, "builtin function {}", name),
+      Object::Quote(e) => write!(f, "QUOTE({})", e),
+      Object::Macro { parameters, .. } => write!(f, "macro({}) {{...}}", parameters.join(", ")),
+      Object::CompiledFunction(function) =>
+        write!(f, "compiled function/{}", function.num_parameters),
+      Object::Closure(closure) => write!(f, "closure/{}", closure.function.num_parameters),
+      Object::ReturnValue(o) => write!(f, "{}", o),
+      Object::Error(message) => write!(f, "ERROR: {}", message),
+    }
+  }
+}
+
+pub type Env = Rc<RefCell<Environment>>;
+
+#[derive(Default)]
+pub struct Environment {
+  store: HashMap<String, Object>,
+  outer: Option<Env>,
+}
+
+impl Environment {
+  pub fn new() -> Env {
+    Rc::new(RefCell::new(Environment::default()))
+  }
+
+  pub fn new_enclosed(outer: &Env) -> Env {
+    Rc::new(RefCell::new(Environment {
+      store: HashMap::new(),
+      outer: Some(Rc::clone(outer)),
+    }))
+  }
+
+  pub fn get(&self, name: &str) -> Option<Object> {
+    match self.store.get(name) {
+      Some(o) => Some(o.clone()),
+      None => self.outer.as_ref().and_then(|outer| outer.borrow().get(name)),
+    }
+  }
+
+  /// The bindings made in this environment itself, to put back later with `restore`.
+  pub(crate) fn bindings(&self) -> HashMap<String, Object> {
+    self.store.clone()
+  }
+
+  pub(crate) fn restore(&mut self, bindings: HashMap<String, Object>) {
+    self.store = bindings;
+  }
+
+  /// Every name visible from this environment, including those in enclosing scopes.
+  pub fn names(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.store.keys().cloned().collect();
+    if let Some(outer) = &self.outer {
+      names.extend(outer.borrow().names());
+    }
+    names.sort();
+    names.dedup();
+    names
+  }
+
+  pub fn set(&mut self, name: &str, value: Object) {
+    self.store.insert(String::from(name), value);
+  }
+
+  /// Rebinds `name` in the nearest scope that defines it, returning false if none does.
+  pub fn set_existing(&mut self, name: &str, value: Object) -> bool {
+    if let Some(slot) = self.store.get_mut(name) {
+      *slot = value;
+      return true;
+    }
+    match &self.outer {
+      Some(outer) => outer.borrow_mut().set_existing(name, value),
+      None => false,
+    }
+  }
+
+  /// Makes a host function callable from Monkey code under `name`.
+  pub fn register_builtin(&mut self, name: &str, function: BuiltinFunction) {
+    self.set(name, Object::Builtin { name: String::from(name), function: function.into() });
+  }
+
+  /// Like `register_builtin`, but for any Rust function or closure of the forms `NativeFn`
+  /// describes, which check the arguments they're called with and convert them.
+  pub fn register_fn<Args, F: NativeFn<Args>>(&mut self, name: &str, function: F) {
+    self.set(name, Object::native(name, function));
+  }
+}
+
+// Printing the bindings could recurse forever through closures, so only the names are shown.
+impl fmt::Debug for Environment {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut names: Vec<&String> = self.store.keys().collect();
+    names.sort();
+    f.debug_struct("Environment")
+      .field("names", &names)
+      .field("has_outer", &self.outer.is_some())
+      .finish()
+  }
+}
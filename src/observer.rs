@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lexer::Span;
+use crate::object::Object;
+
+/// Watches scripts run, for a host that logs or traces them; see `Interpreter::set_observer`.
+/// Every event does nothing by default but `output`, which prints to stdout as `puts` does
+/// without an observer.
+pub trait Observer {
+  /// A script is about to run.
+  fn script_started(&mut self, _source: &str) {}
+
+  /// A top-level statement starting at `span` is about to run.
+  fn statement(&mut self, _span: Span) {}
+
+  /// A function or builtin is about to be called with `args`.
+  fn function_called(&mut self, _function: &Object, _args: &[Object]) {}
+
+  /// A line that `puts` printed.
+  fn output(&mut self, text: &str) {
+    println!("{}", text);
+  }
+}
+
+pub type SharedObserver = Rc<RefCell<dyn Observer>>;
+
+/// Rewrites text showing values before anyone sees it, as when masking what look like secrets;
+/// see `Interpreter::set_redactor`.
+pub type Redactor = Rc<dyn Fn(&str) -> String>;
+
+/// What a host has asked to hear from, or do to, the programs a session runs.
+#[derive(Clone, Default)]
+pub struct Hooks {
+  pub observer: Option<SharedObserver>,
+  pub redactor: Option<Redactor>,
+}
+
+impl Hooks {
+  pub fn redact(&self, text: &str) -> String {
+    match &self.redactor {
+      Some(redactor) => redactor(text),
+      None => String::from(text),
+    }
+  }
+}
+
+// The hooks of whatever is running on this thread, with the spans of its top-level statements.
+// Builtins are bare functions, so this is how `puts` finds them.
+struct Watch {
+  hooks: Hooks,
+  spans: Rc<[Span]>,
+}
+
+thread_local! {
+  static WATCH: RefCell<Option<Watch>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `hooks` applying to it, given where each top-level statement of the program it
+/// runs starts. Whatever hooks were applying before apply again afterwards.
+pub(crate) fn observing<T>(hooks: &Hooks, spans: &[Span], f: impl FnOnce() -> T) -> T {
+  let watch = Watch { hooks: hooks.clone(), spans: spans.into() };
+  let outer = WATCH.with(|current| current.replace(Some(watch)));
+  let result = f();
+  WATCH.with(|current| current.replace(outer));
+  result
+}
+
+fn current() -> Option<(Hooks, Rc<[Span]>)> {
+  WATCH.with(|current| current.borrow().as_ref().map(|watch| (watch.hooks.clone(), Rc::clone(&watch.spans))))
+}
+
+// Tells the current observer of an event, unless there is none or it is busy with another, as
+// when it runs Monkey itself.
+fn notify(event: impl FnOnce(&mut dyn Observer, &[Span])) -> bool {
+  match current().and_then(|(hooks, spans)| Some((hooks.observer?, spans))) {
+    Some((observer, spans)) => {
+      if let Ok(mut observer) = observer.try_borrow_mut() {
+        event(&mut *observer, &spans);
+      }
+      true
+    },
+    None => false,
+  }
+}
+
+pub(crate) fn statement(index: usize) {
+  notify(|observer, spans| {
+    if let Some(span) = spans.get(index) {
+      observer.statement(*span);
+    }
+  });
+}
+
+pub(crate) fn function_called(function: &Object, args: &[Object]) {
+  notify(|observer, _| observer.function_called(function, args));
+}
+
+/// Prints a line of a script's output, redacted, to the observer if there is one.
+pub(crate) fn output(text: &str) {
+  let text = match current() {
+    Some((hooks, _)) => hooks.redact(text),
+    None => String::from(text),
+  };
+  if !notify(|observer, _| observer.output(&text)) {
+    println!("{}", text);
+  }
+}
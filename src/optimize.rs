@@ -0,0 +1,206 @@
+use crate::ast::*;
+use crate::code::{self, OpCode};
+use crate::eval;
+use crate::object::Object;
+
+/// Replaces operators applied to literals with the literal they evaluate to, innermost first.
+/// Anything that would be a runtime error, such as `1 / 0`, is left for the runtime to report.
+pub fn fold_constants(program: &mut Program) {
+  fold_statements(&mut program.statements);
+}
+
+fn fold_statements(statements: &mut [Statement]) {
+  for statement in statements {
+    match statement {
+      Statement::Let(_, e) | Statement::Assign(_, e) | Statement::Return(e) | Statement::Expression(e) =>
+        fold_expression(e),
+    }
+  }
+}
+
+fn fold_expression(expression: &mut Expression) {
+  let folded = match expression {
+    Expression::Identifier(_) | Expression::IntegerLiteral(_) | Expression::FloatLiteral(_)
+      | Expression::StringLiteral(_) | Expression::Boolean(_) => None,
+    Expression::Prefix(operator, right) => {
+      fold_expression(right);
+      Object::from_literal(right).and_then(|r| eval::eval_prefix_expression(*operator, r).into_literal())
+    },
+    Expression::Infix(operator, left, right) => {
+      fold_expression(left);
+      fold_expression(right);
+      match (Object::from_literal(left), Object::from_literal(right)) {
+        (Some(l), Some(r)) => eval::eval_infix_expression(*operator, l, r).into_literal(),
+        _ => None,
+      }
+    },
+    Expression::ArrayLiteral(elements) => {
+      elements.iter_mut().for_each(fold_expression);
+      None
+    },
+    Expression::HashLiteral(pairs) => {
+      for (k, v) in pairs {
+        fold_expression(k);
+        fold_expression(v);
+      }
+      None
+    },
+    Expression::If { condition, consequence, alternative } => {
+      fold_expression(condition);
+      fold_statements(&mut consequence.statements);
+      if let Some(alternative) = alternative {
+        fold_statements(&mut alternative.statements);
+      }
+      None
+    },
+    Expression::While { condition, body } => {
+      fold_expression(condition);
+      fold_statements(&mut body.statements);
+      None
+    },
+    Expression::FunctionLiteral { body, .. } | Expression::MacroLiteral { body, .. } => {
+      fold_statements(&mut body.statements);
+      None
+    },
+    Expression::Call { function, arguments } => {
+      fold_expression(function);
+      arguments.iter_mut().for_each(fold_expression);
+      None
+    },
+    Expression::Index { left, index } => {
+      fold_expression(left);
+      fold_expression(index);
+      None
+    },
+    Expression::Slice { left, start, end } => {
+      fold_expression(left);
+      start.iter_mut().chain(end).for_each(|b| fold_expression(b));
+      None
+    },
+  };
+  if let Some(folded) = folded {
+    *expression = folded;
+  }
+}
+
+/// A peephole pass over compiled instructions: a jump landing on an unconditional jump goes
+/// straight to where that one goes, instructions nothing can reach are dropped, and so is a jump
+/// to the instruction right after it. Returns the instructions along with the new offset of
+/// each old instruction, and of the end, so that anything else pointing into them can follow.
+pub fn peephole(instructions: &[u8]) -> (Vec<u8>, Vec<usize>) {
+  let mut decoded = vec!();
+  let mut offset = 0;
+  while offset < instructions.len() {
+    let op = OpCode::from_byte(instructions[offset]).expect("peephole over an unknown opcode");
+    let (operands, read) = code::read_operands(op, &instructions[offset + 1..]);
+    decoded.push((offset, op, operands));
+    offset += 1 + read;
+  }
+  // the instruction at a jump target, which is past the last one for a jump to the end
+  let index_at = |decoded: &[(usize, OpCode, Vec<usize>)], offset: usize| {
+    decoded.binary_search_by_key(&offset, |(o, _, _)| *o).unwrap_or(decoded.len())
+  };
+
+  for i in 0..decoded.len() {
+    if !is_jump(decoded[i].1) {
+      continue;
+    }
+    // bounded, since jumps can form a loop
+    for _ in 0..decoded.len() {
+      let target = index_at(&decoded, decoded[i].2[0]);
+      match decoded.get(target) {
+        Some((_, OpCode::Jump, operands)) if target != i => decoded[i].2[0] = operands[0],
+        _ => break,
+      }
+    }
+  }
+
+  let mut kept = vec!(false; decoded.len());
+  let mut pending = vec!(0);
+  while let Some(i) = pending.pop() {
+    if i >= decoded.len() || kept[i] {
+      continue;
+    }
+    kept[i] = true;
+    let (_, op, operands) = &decoded[i];
+    if is_jump(*op) {
+      pending.push(index_at(&decoded, operands[0]));
+    }
+    if !matches!(op, OpCode::Jump | OpCode::ReturnValue | OpCode::Return) {
+      pending.push(i + 1);
+    }
+  }
+  for i in 0..decoded.len() {
+    let (offset, op, operands) = &decoded[i];
+    if kept[i] && *op == OpCode::Jump && operands[0] > *offset {
+      let target = index_at(&decoded, operands[0]);
+      kept[i] = kept[i + 1..target].iter().any(|k| *k);
+    }
+  }
+
+  let mut offsets = vec!(0; instructions.len() + 1);
+  let mut new_offset = 0;
+  for (i, (offset, op, _)) in decoded.iter().enumerate() {
+    offsets[*offset] = new_offset;
+    if kept[i] {
+      new_offset += 1 + op.operand_widths().iter().sum::<usize>();
+    }
+  }
+  offsets[instructions.len()] = new_offset;
+
+  let mut optimized = Vec::with_capacity(new_offset);
+  for (i, (_, op, operands)) in decoded.iter().enumerate() {
+    if !kept[i] {
+      continue;
+    }
+    let operands: Vec<usize> = match op {
+      op if is_jump(*op) => vec!(offsets[operands[0]]),
+      _ => operands.clone(),
+    };
+    optimized.extend(code::make(*op, &operands));
+  }
+  (optimized, offsets)
+}
+
+fn is_jump(op: OpCode) -> bool {
+  matches!(op, OpCode::Jump | OpCode::JumpNotTruthy)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn folded(input: &str) -> Program {
+    let mut program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    fold_constants(&mut program);
+    program
+  }
+
+  fn parsed(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap()
+  }
+
+  #[test]
+  fn test_fold_constants() {
+    let cases = [
+      ("1 + 2 * 3", "7"),
+      ("-(4 - 6)", "2"),
+      ("!(1 < 2) == false", "true"),
+      ("1 <= 2 && 3 >= 4 || false", "false"),
+      ("1.5 * 2", "3.0"),
+      (r#""mon" + "key""#, r#""monkey""#),
+      ("let f = fn(x) { x * (2 + 3) }", "let f = fn(x) { x * 5 }"),
+      ("[1 + 1, {2 * 2: if (1 > 2) { 3 - 3 }}]", "[2, {4: if (false) { 0 }}]"),
+      // errors are left to happen at runtime
+      ("2 + 1 / 0", "2 + 1 / 0"),
+      ("9223372036854775807 + 1", "9223372036854775807 + 1"),
+      ("1 + true", "1 + true"),
+      ("x + 1 + 2", "x + 1 + 2"),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(folded(input), parsed(expected), "input {:?}", input);
+    }
+  }
+}
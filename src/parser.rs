@@ -0,0 +1,794 @@
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+use crate::ast::*;
+use crate::error::MonkeyError;
+use crate::lexer::{LexError, Lexer, Span, Token};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseErrorKind {
+  UnexpectedToken { expected: Token },
+  ExpectedIdent,
+  NoPrefixParse,
+}
+
+impl ParseErrorKind {
+  /// The stable code this error is reported with; see `diagnostics::explain`.
+  pub fn code(&self) -> &'static str {
+    match self {
+      ParseErrorKind::UnexpectedToken { .. } => "E0001",
+      ParseErrorKind::ExpectedIdent => "E0002",
+      ParseErrorKind::NoPrefixParse => "E0003",
+    }
+  }
+}
+
+/// A parse error along with the offending token and where it starts.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+  pub kind: ParseErrorKind,
+  pub token: Token,
+  pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}: error[{}]: ", self.span.line, self.span.column, self.kind.code())?;
+    match &self.kind {
+      ParseErrorKind::UnexpectedToken { expected } =>
+        write!(f, "expected next token to be {:?}, got {:?} instead", expected, self.token),
+      ParseErrorKind::ExpectedIdent =>
+        write!(f, "expected next token to be an identifier, got {:?} instead", self.token),
+      ParseErrorKind::NoPrefixParse =>
+        write!(f, "no prefix parse function for {:?} found", self.token),
+    }
+  }
+}
+
+impl Error for ParseError {}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// Parses a whole program; see `Parser::parse_program`.
+pub fn parse(source: &str) -> Result<Program, MonkeyError> {
+  Parser::new(Lexer::new(source)).parse_program()
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub(crate) enum Precedence {
+  Lowest,
+  Or,
+  And,
+  Equals,
+  LessGreater,
+  BitOr,
+  BitXor,
+  BitAnd,
+  Shift,
+  Sum,
+  Product,
+  Prefix,
+  Call,
+  Index,
+}
+
+fn precedence_of(t: &Token) -> Precedence {
+  match t {
+    Token::LParen => Precedence::Call,
+    Token::LBracket => Precedence::Index,
+    t => infix_operator(t).map_or(Precedence::Lowest, infix_precedence),
+  }
+}
+
+/// How tightly an infix operator binds; all of them are left-associative.
+pub(crate) fn infix_precedence(operator: InfixOperator) -> Precedence {
+  match operator {
+    InfixOperator::Or => Precedence::Or,
+    InfixOperator::And => Precedence::And,
+    InfixOperator::Eq | InfixOperator::NotEq => Precedence::Equals,
+    InfixOperator::LT | InfixOperator::GT | InfixOperator::LtEq | InfixOperator::GtEq =>
+      Precedence::LessGreater,
+    InfixOperator::Pipe => Precedence::BitOr,
+    InfixOperator::Caret => Precedence::BitXor,
+    InfixOperator::Ampersand => Precedence::BitAnd,
+    InfixOperator::ShiftLeft | InfixOperator::ShiftRight => Precedence::Shift,
+    InfixOperator::Plus | InfixOperator::Minus => Precedence::Sum,
+    InfixOperator::Asterisk | InfixOperator::Slash | InfixOperator::TildeSlash
+      | InfixOperator::Percent => Precedence::Product,
+  }
+}
+
+fn infix_operator(t: &Token) -> Option<InfixOperator> {
+  match t {
+    Token::Plus => Some(InfixOperator::Plus),
+    Token::Minus => Some(InfixOperator::Minus),
+    Token::Asterisk => Some(InfixOperator::Asterisk),
+    Token::Slash => Some(InfixOperator::Slash),
+    Token::TildeSlash => Some(InfixOperator::TildeSlash),
+    Token::Percent => Some(InfixOperator::Percent),
+    Token::Ampersand => Some(InfixOperator::Ampersand),
+    Token::Pipe => Some(InfixOperator::Pipe),
+    Token::Caret => Some(InfixOperator::Caret),
+    Token::ShiftLeft => Some(InfixOperator::ShiftLeft),
+    Token::ShiftRight => Some(InfixOperator::ShiftRight),
+    Token::GT => Some(InfixOperator::GT),
+    Token::LT => Some(InfixOperator::LT),
+    Token::GtEq => Some(InfixOperator::GtEq),
+    Token::LtEq => Some(InfixOperator::LtEq),
+    Token::And => Some(InfixOperator::And),
+    Token::Or => Some(InfixOperator::Or),
+    Token::Eq => Some(InfixOperator::Eq),
+    Token::NotEq => Some(InfixOperator::NotEq),
+    _ => None,
+  }
+}
+
+/// A Pratt parser over the tokens produced by a `Lexer`. The lexer signals the end of input by
+/// returning `None`; the parser represents that as `Token::EOF`, positioned at the end of input.
+///
+/// After an error the parser skips ahead to the end of the statement (a `;`, or the `}` closing
+/// the enclosing block) and carries on, so `errors` reports every problem it finds.
+pub struct Parser<'a> {
+  lexer: Lexer<'a>,
+  cur_token: Token,
+  cur_span: Span,
+  peek_token: Token,
+  peek_span: Span,
+  errors: Vec<ParseError>,
+  statement_spans: Vec<Span>,
+}
+
+impl<'a> Parser<'a> {
+  pub fn new(lexer: Lexer<'a>) -> Self {
+    let start = Span { line: 1, column: 1, byte_offset: 0 };
+    let mut p = Parser {
+      lexer,
+      cur_token: Token::EOF,
+      cur_span: start,
+      peek_token: Token::EOF,
+      peek_span: start,
+      errors: vec!(),
+      statement_spans: vec!(),
+    };
+    p.next_token();
+    p.next_token();
+    p
+  }
+
+  pub fn errors(&self) -> &[ParseError] {
+    &self.errors
+  }
+
+  /// Where each top-level statement of the parsed program starts.
+  pub fn statement_spans(&self) -> &[Span] {
+    &self.statement_spans
+  }
+
+  /// Why any `Token::Illegal` among the tokens read so far was illegal.
+  pub fn lex_errors(&self) -> &[LexError] {
+    self.lexer.errors()
+  }
+
+  /// Parses a whole program, failing with every lex and parse error in it. An illegal token
+  /// also breaks the statement around it, so a parse error at one goes unreported in favour of
+  /// the lex error saying why it is illegal.
+  pub fn parse_program(&mut self) -> Result<Program, MonkeyError> {
+    let program = self.parse_statements();
+    if self.lex_errors().is_empty() && self.errors.is_empty() {
+      return Ok(program);
+    }
+    let parse = self.errors.iter().filter(|e| e.token != Token::Illegal).cloned().collect();
+    Err(MonkeyError::Syntax { lex: self.lex_errors().to_vec(), parse })
+  }
+
+  // Parses as much as it can, recording errors and carrying on past them.
+  fn parse_statements(&mut self) -> Program {
+    let mut statements = vec!();
+    while self.cur_token != Token::EOF {
+      let span = self.cur_span;
+      match self.parse_statement() {
+        Ok(s) => {
+          statements.push(s);
+          self.statement_spans.push(span);
+        },
+        Err(e) => {
+          self.errors.push(e);
+          self.synchronize();
+        },
+      }
+      self.next_token();
+    }
+    Program { statements }
+  }
+
+  fn next_token(&mut self) {
+    let (next, next_span) = match self.lexer.next() {
+      Some(t) => (t.token, t.span),
+      None => (Token::EOF, self.lexer.span()),
+    };
+    self.cur_token = mem::replace(&mut self.peek_token, next);
+    self.cur_span = mem::replace(&mut self.peek_span, next_span);
+  }
+
+  // Advances to the `;` ending the current statement or the `}` closing the enclosing block,
+  // stepping over any nested blocks on the way.
+  fn synchronize(&mut self) {
+    let mut depth = 0;
+    loop {
+      match self.cur_token {
+        Token::EOF => return,
+        Token::Semicolon | Token::RBrace if depth == 0 => return,
+        Token::LBrace => depth += 1,
+        Token::RBrace => depth -= 1,
+        _ => {},
+      }
+      self.next_token();
+    }
+  }
+
+  fn cur_error(&self, kind: ParseErrorKind) -> ParseError {
+    ParseError { kind, token: self.cur_token.clone(), span: self.cur_span }
+  }
+
+  fn peek_error(&self, kind: ParseErrorKind) -> ParseError {
+    ParseError { kind, token: self.peek_token.clone(), span: self.peek_span }
+  }
+
+  fn expect_peek(&mut self, expected: Token) -> ParseResult<()> {
+    if self.peek_token == expected {
+      self.next_token();
+      Ok(())
+    } else {
+      Err(self.peek_error(ParseErrorKind::UnexpectedToken { expected }))
+    }
+  }
+
+  fn expect_peek_ident(&mut self) -> ParseResult<String> {
+    if let Token::Ident(name) = &self.peek_token {
+      let name = name.clone();
+      self.next_token();
+      Ok(name)
+    } else {
+      Err(self.peek_error(ParseErrorKind::ExpectedIdent))
+    }
+  }
+
+  fn skip_peek_semicolon(&mut self) {
+    if self.peek_token == Token::Semicolon {
+      self.next_token();
+    }
+  }
+
+  fn parse_statement(&mut self) -> ParseResult<Statement> {
+    match self.cur_token {
+      Token::Let => self.parse_let_statement(),
+      Token::Return => self.parse_return_statement(),
+      Token::Ident(_) if self.peek_token == Token::Assign => self.parse_assign_statement(),
+      _ => self.parse_expression_statement(),
+    }
+  }
+
+  fn parse_let_statement(&mut self) -> ParseResult<Statement> {
+    let name = self.expect_peek_ident()?;
+    self.expect_peek(Token::Assign)?;
+    self.next_token();
+    let value = match self.cur_token {
+      Token::Struct => self.parse_struct(&name)?,
+      _ => self.parse_expression(Precedence::Lowest)?,
+    };
+    self.skip_peek_semicolon();
+    Ok(Statement::Let(name, value))
+  }
+
+  fn parse_assign_statement(&mut self) -> ParseResult<Statement> {
+    let name = match &self.cur_token {
+      Token::Ident(name) => name.clone(),
+      _ => unreachable!("assignment to a non-identifier"),
+    };
+    self.next_token();
+    self.next_token();
+    let value = self.parse_expression(Precedence::Lowest)?;
+    self.skip_peek_semicolon();
+    Ok(Statement::Assign(name, value))
+  }
+
+  fn parse_return_statement(&mut self) -> ParseResult<Statement> {
+    self.next_token();
+    let value = self.parse_expression(Precedence::Lowest)?;
+    self.skip_peek_semicolon();
+    Ok(Statement::Return(value))
+  }
+
+  fn parse_expression_statement(&mut self) -> ParseResult<Statement> {
+    let expression = self.parse_expression(Precedence::Lowest)?;
+    self.skip_peek_semicolon();
+    Ok(Statement::Expression(expression))
+  }
+
+  fn parse_block_statement(&mut self) -> ParseResult<BlockStatement> {
+    let mut statements = vec!();
+    self.next_token();
+    while self.cur_token != Token::RBrace {
+      if self.cur_token == Token::EOF {
+        return Err(self.cur_error(ParseErrorKind::UnexpectedToken { expected: Token::RBrace }));
+      }
+      match self.parse_statement() {
+        Ok(s) => statements.push(s),
+        Err(e) => {
+          self.errors.push(e);
+          self.synchronize();
+          if self.cur_token == Token::RBrace {
+            continue;
+          }
+        },
+      }
+      self.next_token();
+    }
+    Ok(BlockStatement { statements })
+  }
+
+  fn parse_expression(&mut self, precedence: Precedence) -> ParseResult<Expression> {
+    let mut left = self.parse_prefix()?;
+    while self.peek_token != Token::Semicolon && precedence < precedence_of(&self.peek_token) {
+      self.next_token();
+      left = self.parse_infix(left)?;
+    }
+    Ok(left)
+  }
+
+  fn parse_prefix(&mut self) -> ParseResult<Expression> {
+    match &self.cur_token {
+      Token::Ident(name) => Ok(Expression::Identifier(name.clone())),
+      Token::Int(value) => Ok(Expression::IntegerLiteral(*value)),
+      Token::Float(value) => Ok(Expression::FloatLiteral(*value)),
+      Token::Str(value) => Ok(Expression::StringLiteral(value.clone())),
+      Token::True => Ok(Expression::Boolean(true)),
+      Token::False => Ok(Expression::Boolean(false)),
+      Token::Bang => self.parse_prefix_expression(PrefixOperator::Bang),
+      Token::Minus => self.parse_prefix_expression(PrefixOperator::Minus),
+      Token::LParen => self.parse_grouped_expression(),
+      Token::LBracket => {
+        let elements = self.parse_expression_list(Token::RBracket)?;
+        Ok(Expression::ArrayLiteral(elements))
+      },
+      Token::LBrace => self.parse_hash_literal(),
+      Token::If => self.parse_if_expression(),
+      Token::While => self.parse_while_expression(),
+      Token::Function => self.parse_function_literal(),
+      Token::Macro => self.parse_macro_literal(),
+      _ => Err(self.cur_error(ParseErrorKind::NoPrefixParse)),
+    }
+  }
+
+  fn parse_infix(&mut self, left: Expression) -> ParseResult<Expression> {
+    match self.cur_token {
+      Token::LParen => return self.parse_call_expression(left),
+      Token::LBracket => return self.parse_index_expression(left),
+      _ => {},
+    }
+    let operator = match infix_operator(&self.cur_token) {
+      Some(op) => op,
+      None => return Err(self.cur_error(ParseErrorKind::NoPrefixParse)),
+    };
+    let precedence = precedence_of(&self.cur_token);
+    self.next_token();
+    let right = self.parse_expression(precedence)?;
+    Ok(Expression::Infix(operator, Box::new(left), Box::new(right)))
+  }
+
+  fn parse_prefix_expression(&mut self, operator: PrefixOperator) -> ParseResult<Expression> {
+    self.next_token();
+    let right = self.parse_expression(Precedence::Prefix)?;
+    Ok(Expression::Prefix(operator, Box::new(right)))
+  }
+
+  fn parse_grouped_expression(&mut self) -> ParseResult<Expression> {
+    self.next_token();
+    let expression = self.parse_expression(Precedence::Lowest)?;
+    self.expect_peek(Token::RParen)?;
+    Ok(expression)
+  }
+
+  fn parse_if_expression(&mut self) -> ParseResult<Expression> {
+    self.expect_peek(Token::LParen)?;
+    self.next_token();
+    let condition = self.parse_expression(Precedence::Lowest)?;
+    self.expect_peek(Token::RParen)?;
+    self.expect_peek(Token::LBrace)?;
+    let consequence = self.parse_block_statement()?;
+    let alternative = if self.peek_token == Token::Else {
+      self.next_token();
+      self.expect_peek(Token::LBrace)?;
+      Some(self.parse_block_statement()?)
+    } else {
+      None
+    };
+    Ok(Expression::If { condition: Box::new(condition), consequence, alternative })
+  }
+
+  fn parse_while_expression(&mut self) -> ParseResult<Expression> {
+    self.expect_peek(Token::LParen)?;
+    self.next_token();
+    let condition = self.parse_expression(Precedence::Lowest)?;
+    self.expect_peek(Token::RParen)?;
+    self.expect_peek(Token::LBrace)?;
+    let body = self.parse_block_statement()?;
+    Ok(Expression::While { condition: Box::new(condition), body })
+  }
+
+  fn parse_function_literal(&mut self) -> ParseResult<Expression> {
+    let name = match &self.peek_token {
+      Token::Ident(name) => Some(name.clone()),
+      _ => None,
+    };
+    if name.is_some() {
+      self.next_token();
+    }
+    let (parameters, body) = self.parse_parameters_and_body()?;
+    Ok(Expression::FunctionLiteral { name, parameters, body })
+  }
+
+  fn parse_macro_literal(&mut self) -> ParseResult<Expression> {
+    let (parameters, body) = self.parse_parameters_and_body()?;
+    Ok(Expression::MacroLiteral { parameters, body })
+  }
+
+  // Parses the `(a, b) { ... }` following `fn` or `macro`.
+  fn parse_parameters_and_body(&mut self) -> ParseResult<(Vec<String>, BlockStatement)> {
+    self.expect_peek(Token::LParen)?;
+    let parameters = self.parse_function_parameters()?;
+    self.expect_peek(Token::LBrace)?;
+    let body = self.parse_block_statement()?;
+    Ok((parameters, body))
+  }
+
+  fn parse_function_parameters(&mut self) -> ParseResult<Vec<String>> {
+    let mut parameters = vec!();
+    if self.peek_token == Token::RParen {
+      self.next_token();
+      return Ok(parameters);
+    }
+    parameters.push(self.expect_peek_ident()?);
+    while self.peek_token == Token::Comma {
+      self.next_token();
+      parameters.push(self.expect_peek_ident()?);
+    }
+    self.expect_peek(Token::RParen)?;
+    Ok(parameters)
+  }
+
+  // `let Point = struct { x, y };` is sugar for a constructor that takes the fields in order
+  // and returns a hash of them, tagged with the struct's name under `STRUCT_TYPE_KEY`.
+  fn parse_struct(&mut self, name: &str) -> ParseResult<Expression> {
+    self.expect_peek(Token::LBrace)?;
+    let mut fields = vec!();
+    while self.peek_token != Token::RBrace {
+      fields.push(self.expect_peek_ident()?);
+      if self.peek_token != Token::RBrace {
+        self.expect_peek(Token::Comma)?;
+      }
+    }
+    self.next_token();
+
+    let string = |s: &str| Expression::StringLiteral(String::from(s));
+    let mut pairs = vec!((string(STRUCT_TYPE_KEY), string(name)));
+    pairs.extend(fields.iter().map(|f| (string(f), Expression::Identifier(f.clone()))));
+    let body = BlockStatement { statements: vec!(Statement::Expression(Expression::HashLiteral(pairs))) };
+    Ok(Expression::FunctionLiteral { name: None, parameters: fields, body })
+  }
+
+  fn parse_call_expression(&mut self, function: Expression) -> ParseResult<Expression> {
+    let arguments = self.parse_expression_list(Token::RParen)?;
+    Ok(Expression::Call { function: Box::new(function), arguments })
+  }
+
+  fn parse_hash_literal(&mut self) -> ParseResult<Expression> {
+    let mut pairs = vec!();
+    while self.peek_token != Token::RBrace {
+      self.next_token();
+      let key = self.parse_expression(Precedence::Lowest)?;
+      self.expect_peek(Token::Colon)?;
+      self.next_token();
+      let value = self.parse_expression(Precedence::Lowest)?;
+      pairs.push((key, value));
+      if self.peek_token != Token::RBrace {
+        self.expect_peek(Token::Comma)?;
+      }
+    }
+    self.expect_peek(Token::RBrace)?;
+    Ok(Expression::HashLiteral(pairs))
+  }
+
+  // Parses `left[index]`, or a slice `left[start:end]` with either bound optional.
+  fn parse_index_expression(&mut self, left: Expression) -> ParseResult<Expression> {
+    let left = Box::new(left);
+    let start = if self.peek_token == Token::Colon {
+      None
+    } else {
+      self.next_token();
+      Some(Box::new(self.parse_expression(Precedence::Lowest)?))
+    };
+    if self.peek_token != Token::Colon {
+      self.expect_peek(Token::RBracket)?;
+      let index = start.expect("an index is parsed unless a colon follows the bracket");
+      return Ok(Expression::Index { left, index });
+    }
+    self.next_token();
+    let end = if self.peek_token == Token::RBracket {
+      None
+    } else {
+      self.next_token();
+      Some(Box::new(self.parse_expression(Precedence::Lowest)?))
+    };
+    self.expect_peek(Token::RBracket)?;
+    Ok(Expression::Slice { left, start, end })
+  }
+
+  fn parse_expression_list(&mut self, end: Token) -> ParseResult<Vec<Expression>> {
+    let mut list = vec!();
+    if self.peek_token == end {
+      self.next_token();
+      return Ok(list);
+    }
+    self.next_token();
+    list.push(self.parse_expression(Precedence::Lowest)?);
+    while self.peek_token == Token::Comma {
+      self.next_token();
+      self.next_token();
+      list.push(self.parse_expression(Precedence::Lowest)?);
+    }
+    self.expect_peek(end)?;
+    Ok(list)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn parse(input: &str) -> Program {
+    Parser::new(Lexer::new(input)).parse_program().unwrap_or_else(|e| {
+      panic!("parse errors for {:?}: {}", input, e)
+    })
+  }
+
+  fn parse_expression(input: &str) -> Expression {
+    let mut program = parse(input);
+    assert_eq!(program.statements.len(), 1);
+    match program.statements.pop() {
+      Some(Statement::Expression(e)) => e,
+      s => panic!("expected an expression statement, got {:?}", s),
+    }
+  }
+
+  fn ident(name: &str) -> Box<Expression> {
+    Box::new(Expression::Identifier(String::from(name)))
+  }
+
+  fn int(value: i64) -> Box<Expression> {
+    Box::new(Expression::IntegerLiteral(value))
+  }
+
+  #[test]
+  fn test_let_and_return_statements() {
+    let program = parse("
+      let x = 5;
+      let foobar = y;
+      foobar = x
+      return x + 1;
+    ");
+    assert_eq!(program.statements, vec!(
+      Statement::Let(String::from("x"), *int(5)),
+      Statement::Let(String::from("foobar"), *ident("y")),
+      Statement::Assign(String::from("foobar"), *ident("x")),
+      Statement::Return(Expression::Infix(InfixOperator::Plus, ident("x"), int(1))),
+    ));
+  }
+
+  #[test]
+  fn test_prefix_and_infix_expressions() {
+    assert_eq!(parse_expression("!true;"), Expression::Prefix(
+      PrefixOperator::Bang, Box::new(Expression::Boolean(true)),
+    ));
+    assert_eq!(parse_expression("-a"), Expression::Prefix(PrefixOperator::Minus, ident("a")));
+    assert_eq!(parse_expression("5 != 3"), Expression::Infix(InfixOperator::NotEq, int(5), int(3)));
+    assert_eq!(parse_expression(r#""a" + "b""#), Expression::Infix(
+      InfixOperator::Plus,
+      Box::new(Expression::StringLiteral(String::from("a"))),
+      Box::new(Expression::StringLiteral(String::from("b"))),
+    ));
+  }
+
+  #[test]
+  fn test_operator_precedence() {
+    // grouping leaves no trace in the AST, so explicit parens give the expected tree
+    let cases = [
+      ("-a * b", "((-a) * b)"),
+      ("!-a", "(!(-a))"),
+      ("a + b - c", "((a + b) - c)"),
+      ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f)"),
+      ("5 > 4 == 3 < 4", "((5 > 4) == (3 < 4))"),
+      ("5 >= 4 == 3 <= 4", "((5 >= 4) == (3 <= 4))"),
+      ("a || b && c == d", "(a || (b && (c == d)))"),
+      ("a && b || c && d", "((a && b) || (c && d))"),
+      ("a || b || c", "((a || b) || c)"),
+      ("a % b * c", "((a % b) * c)"),
+      ("n % 3 == 0", "((n % 3) == 0)"),
+      ("a | b ^ c & d", "(a | (b ^ (c & d)))"),
+      ("a & 1 << b + 1", "(a & (1 << (b + 1)))"),
+      ("a >> 1 < b | c", "((a >> 1) < (b | c))"),
+      ("3 + 4 * 5 == 3 * 1 + 4 * 5", "((3 + (4 * 5)) == ((3 * 1) + (4 * 5)))"),
+      ("1 + (2 + 3) + 4", "((1 + (2 + 3)) + 4)"),
+      ("-(5 + 5)", "(-(5 + 5))"),
+      ("a + add(b * c) + d", "((a + add((b * c))) + d)"),
+      ("add(a + b + c * d / f + g)", "add((((a + b) + ((c * d) / f)) + g))"),
+      ("a * [1, 2, 3, 4][b * c] * d", "((a * ([1, 2, 3, 4][(b * c)])) * d)"),
+      ("add(a * b[2], b[1], 2 * [1, 2][1])", "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])))"),
+    ];
+    for (input, expected) in cases.iter() {
+      assert_eq!(parse(input), parse(expected), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_if_expression() {
+    assert_eq!(parse_expression("if (x < y) { x } else { y; }"), Expression::If {
+      condition: Box::new(Expression::Infix(InfixOperator::LT, ident("x"), ident("y"))),
+      consequence: BlockStatement { statements: vec!(Statement::Expression(*ident("x"))) },
+      alternative: Some(BlockStatement { statements: vec!(Statement::Expression(*ident("y"))) }),
+    });
+  }
+
+  #[test]
+  fn test_while_expression() {
+    assert_eq!(parse_expression("while (x < y) { x; y }"), Expression::While {
+      condition: Box::new(Expression::Infix(InfixOperator::LT, ident("x"), ident("y"))),
+      body: BlockStatement { statements: vec!(
+        Statement::Expression(*ident("x")),
+        Statement::Expression(*ident("y")),
+      )},
+    });
+  }
+
+  #[test]
+  fn test_function_literal_and_call() {
+    assert_eq!(parse_expression("fn(x, y) { x + y; }"), Expression::FunctionLiteral {
+      name: None,
+      parameters: vec!(String::from("x"), String::from("y")),
+      body: BlockStatement { statements: vec!(
+        Statement::Expression(Expression::Infix(InfixOperator::Plus, ident("x"), ident("y"))),
+      )},
+    });
+    assert_eq!(parse_expression("fn() {}"), Expression::FunctionLiteral {
+      name: None,
+      parameters: vec!(),
+      body: BlockStatement { statements: vec!() },
+    });
+    assert_eq!(parse_expression("fn loop() { loop() }").to_string(), "fn loop() { loop() }");
+    assert_eq!(parse_expression("macro(x) { x }"), Expression::MacroLiteral {
+      parameters: vec!(String::from("x")),
+      body: BlockStatement { statements: vec!(Statement::Expression(*ident("x"))) },
+    });
+    assert_eq!(parse_expression("add(1, 2 * 3)"), Expression::Call {
+      function: ident("add"),
+      arguments: vec!(*int(1), Expression::Infix(InfixOperator::Asterisk, int(2), int(3))),
+    });
+  }
+
+  #[test]
+  fn test_array_literal_and_index() {
+    assert_eq!(parse_expression("[1, 2 * 2]"), Expression::ArrayLiteral(vec!(
+      *int(1),
+      Expression::Infix(InfixOperator::Asterisk, int(2), int(2)),
+    )));
+    assert_eq!(parse_expression("[]"), Expression::ArrayLiteral(vec!()));
+    assert_eq!(parse_expression("arr[1 + 1]"), Expression::Index {
+      left: ident("arr"),
+      index: Box::new(Expression::Infix(InfixOperator::Plus, int(1), int(1))),
+    });
+    assert_eq!(parse_expression("s[1:x]"), Expression::Slice {
+      left: ident("s"),
+      start: Some(int(1)),
+      end: Some(ident("x")),
+    });
+    assert_eq!(parse_expression("s[:]"), Expression::Slice { left: ident("s"), start: None, end: None });
+    assert_eq!(parse_expression("s[:2][0]"), Expression::Index {
+      left: Box::new(Expression::Slice { left: ident("s"), start: None, end: Some(int(2)) }),
+      index: int(0),
+    });
+  }
+
+  #[test]
+  fn test_hash_literal() {
+    let s = |v: &str| Expression::StringLiteral(String::from(v));
+    assert_eq!(parse_expression(r#"{"one": 1, two: 1 + 1, 3: true}"#), Expression::HashLiteral(vec!(
+      (s("one"), *int(1)),
+      (*ident("two"), Expression::Infix(InfixOperator::Plus, int(1), int(1))),
+      (*int(3), Expression::Boolean(true)),
+    )));
+    assert_eq!(parse_expression("{}"), Expression::HashLiteral(vec!()));
+
+    let mut p = Parser::new(Lexer::new("{1: 2 3: 4}"));
+    assert!(p.parse_program().is_err());
+    assert_eq!(p.errors()[0].kind, ParseErrorKind::UnexpectedToken { expected: Token::Comma });
+    assert_eq!(p.errors()[0].token, Token::Int(3));
+  }
+
+  #[test]
+  fn test_struct() {
+    let program = Parser::new(Lexer::new("let Point = struct { x, y };")).parse_program().unwrap();
+    assert_eq!(program.to_string(), r#"let Point = fn(x, y) { {"__type": "Point", "x": x, "y": y} };"#);
+    // the name comes from the let, so a struct can't stand alone
+    assert_eq!(errors("struct { x };"), vec!("1:1: error[E0003]: no prefix parse function for Struct found"));
+  }
+
+  fn errors(input: &str) -> Vec<String> {
+    match Parser::new(Lexer::new(input)).parse_program() {
+      Err(MonkeyError::Syntax { lex, parse }) if lex.is_empty() =>
+        parse.iter().map(|e| e.to_string()).collect(),
+      other => panic!("parsing {:?} gave {:?}", input, other),
+    }
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let mut p = Parser::new(Lexer::new("let = 5;"));
+    assert!(p.parse_program().is_err());
+    assert_eq!(p.errors(), &[ParseError {
+      kind: ParseErrorKind::ExpectedIdent,
+      token: Token::Assign,
+      span: Span { line: 1, column: 5, byte_offset: 4 },
+    }]);
+
+    assert_eq!(errors("let x = (1 + 2"), vec!(
+      "1:15: error[E0001]: expected next token to be RParen, got EOF instead",
+    ));
+    assert_eq!(errors("fn(x) { x + 1"), vec!(
+      "1:14: error[E0001]: expected next token to be RBrace, got EOF instead",
+    ));
+  }
+
+  #[test]
+  fn test_error_codes_are_explained() {
+    let kinds = [
+      ParseErrorKind::UnexpectedToken { expected: Token::Assign },
+      ParseErrorKind::ExpectedIdent,
+      ParseErrorKind::NoPrefixParse,
+    ];
+    for kind in kinds.iter() {
+      assert!(crate::diagnostics::explain(kind.code()).is_some(), "{:?}", kind);
+    }
+  }
+
+  #[test]
+  fn test_parse_error_recovery() {
+    let mut p = Parser::new(Lexer::new("
+      let = 5;
+      let x 5;
+      let f = fn(a) {
+        let b = ;
+        a + {1: 2}[1]
+        let = 3;
+      };
+      let y = 10;
+    "));
+    let program = p.parse_statements();
+    let errors: Vec<String> = p.errors().iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec!(
+      "2:11: error[E0002]: expected next token to be an identifier, got Assign instead",
+      "3:13: error[E0001]: expected next token to be Assign, got Int(5) instead",
+      "5:17: error[E0003]: no prefix parse function for Semicolon found",
+      "7:13: error[E0002]: expected next token to be an identifier, got Assign instead",
+    ));
+    // parsing carries on after each error
+    assert_eq!(program.statements.last(), Some(&Statement::Let(String::from("y"), *int(10))));
+    match &program.statements[program.statements.len() - 2] {
+      Statement::Let(name, Expression::FunctionLiteral { body, .. }) => {
+        assert_eq!(name, "f");
+        assert_eq!(body.statements.len(), 1);
+      },
+      s => panic!("expected the function binding to survive, got {:?}", s),
+    }
+  }
+}
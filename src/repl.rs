@@ -0,0 +1,137 @@
+use std::io::{self, BufRead, Write};
+
+use crate::ast::Statement;
+use crate::error::MonkeyError;
+use crate::lint;
+use crate::object::Object;
+use crate::parser;
+use crate::session::{Options, Session};
+
+const PROMPT: &str = ">> ";
+
+/// Reads Monkey source a line at a time from `input`, running each line as `options` say so that
+/// bindings carry over, and writing the result (or any errors) to `output`.
+pub fn start<R: BufRead, W: Write>(input: R, output: W, options: Options) -> io::Result<()> {
+  run(input, output, &mut Session::with_options(options))
+}
+
+/// Like `start`, but runs lines in an existing session, showing values as its redactor has them.
+pub fn run<R: BufRead, W: Write>(input: R, mut output: W, session: &mut Session) -> io::Result<()> {
+  let mut lines = input.lines();
+  loop {
+    write!(output, "{}", PROMPT)?;
+    output.flush()?;
+    let line = match lines.next() {
+      Some(line) => line?,
+      None => return writeln!(output),
+    };
+
+    // `:expand code` shows the code after macro expansion instead of running it
+    let (expand_only, source) = match line.strip_prefix(":expand ") {
+      Some(source) => (true, source),
+      None => (false, line.as_str()),
+    };
+
+    let program = match parser::parse(source) {
+      Ok(program) => program,
+      Err(e) => {
+        for error in e.to_string().lines() {
+          writeln!(output, "\t{}", error)?;
+        }
+        continue;
+      },
+    };
+
+    if expand_only {
+      match session.expand(program) {
+        Ok(program) => writeln!(output, "{}", session.redact(&program.to_string()))?,
+        Err(MonkeyError::Runtime { message, .. }) => writeln!(output, "{}", Object::Error(message))?,
+        Err(e) => writeln!(output, "{}", e)?,
+      }
+      continue;
+    }
+
+    for warning in lint::check_unused_values(&program) {
+      writeln!(output, "warning: {}", warning)?;
+    }
+
+    // a trailing `let` or assignment has no interesting value to show
+    let show_result = !matches!(
+      program.statements.last(), None | Some(Statement::Let(..)) | Some(Statement::Assign(..))
+    );
+    match session.run(program) {
+      Ok(result) if show_result => writeln!(output, "{}", session.redact(&result.to_string()))?,
+      Ok(_) => {},
+      Err(MonkeyError::Runtime { message, .. }) => writeln!(output, "{}", Object::Error(message))?,
+      Err(e) => writeln!(output, "{}", e)?,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::session::Engine;
+
+  fn run_session(input: &str, engine: Engine) -> String {
+    let mut output = vec!();
+    start(input.as_bytes(), &mut output, Options::new(engine)).unwrap();
+    String::from_utf8(output).unwrap()
+  }
+
+  #[test]
+  fn test_session() {
+    let input = "let add = fn(x, y) { x + y };\nadd(2, 3)\nlet x 1\n\n-true\n1 == 2; 3\n";
+    let expected = [
+      ">> ",
+      ">> 5\n",
+      ">> \t1:7: error[E0001]: expected next token to be Assign, got Int(1) instead\n",
+      ">> ",
+      ">> ERROR: unknown operator: -BOOLEAN\n",
+      ">> warning: result of `==` comparison is unused\n3\n",
+      ">> \n",
+    ].concat();
+    assert_eq!(run_session(input, Engine::Eval), expected);
+    assert_eq!(run_session(input, Engine::Vm), expected);
+  }
+
+  #[test]
+  fn test_macros_carry_over() {
+    let input = "let unless = macro(c, a, b) { quote(if (!(unquote(c))) { unquote(a) } else { unquote(b) }) };
+unless(1 > 2, 10, 20)\n";
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      assert_eq!(run_session(input, *engine), ">> >> 10\n>> \n");
+    }
+  }
+
+  #[test]
+  fn test_expand_command() {
+    let input = "let twice = macro(x) { quote(unquote(x) * 2) };
+:expand twice(1 + 2); let y = twice(twice(3))
+:expand let m = macro() { 1 };
+:expand m()
+:expand (1
+twice(4)\n";
+    assert_eq!(run_session(input, Engine::Eval), [
+      ">> ",
+      ">> ((1 + 2) * 2) let y = ((3 * 2) * 2);\n",
+      ">> \n",
+      ">> ERROR: macro `m` must return a QUOTE, got INTEGER\n",
+      ">> \t1:3: error[E0001]: expected next token to be RParen, got EOF instead\n",
+      ">> 8\n",
+      ">> \n",
+    ].concat());
+  }
+
+  #[test]
+  fn test_vm_session_keeps_globals_after_errors() {
+    let output = run_session("let a = 1;\nlet f = fn() { b };\n5 / 0\na + 1\n", Engine::Vm);
+    assert_eq!(output, [
+      ">> ",
+      ">> compile error: undefined variable b\n",
+      ">> ERROR: division by zero\n",
+      ">> 2\n",
+      ">> \n",
+    ].concat());
+  }
+}
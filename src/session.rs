@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+
+use crate::ast::{self, Expression, InfixOperator, Program, Statement};
+use crate::compiler::{Compiler, CompilerOptions, Symbol, SymbolScope, SymbolTable};
+use crate::error::MonkeyError;
+use crate::eval;
+use crate::fmt;
+use crate::lexer::{Lexer, Span};
+use crate::lint;
+use crate::macros;
+use crate::object::{Env, Environment, Object};
+use crate::observer::{self, Hooks, Redactor, SharedObserver};
+use crate::parser::Parser;
+use crate::vm::Vm;
+
+/// Which implementation runs programs: the tree-walking evaluator or the bytecode VM.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Engine {
+  Eval,
+  Vm,
+}
+
+/// How `/` rounds the quotient of two integers. `~/` always rounds down.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Division {
+  /// Toward zero, as in Rust: `-7 / 2` is `-3`.
+  Truncate,
+  /// Down, as in Python: `-7 / 2` is `-4`.
+  Floor,
+}
+
+/// How the REPL and scripts run programs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Options {
+  pub engine: Engine,
+  pub division: Division,
+  /// How the VM's compiler optimizes; the evaluator ignores this.
+  pub compiler: CompilerOptions,
+}
+
+impl Options {
+  pub fn new(engine: Engine) -> Self {
+    Options { engine, division: Division::Truncate, compiler: CompilerOptions::default() }
+  }
+}
+
+// What an engine keeps from one program to the next.
+enum State {
+  Eval(Env),
+  Vm { symbol_table: SymbolTable, constants: Vec<Object>, globals: Vec<Object> },
+}
+
+/// A session's bindings and macros as they were at one point; see `Session::checkpoint`.
+pub struct Checkpoint {
+  state: Saved,
+  macros: HashMap<String, Object>,
+}
+
+enum Saved {
+  Eval(HashMap<String, Object>),
+  Vm { symbol_table: SymbolTable, constants: Vec<Object>, globals: Vec<Object> },
+}
+
+/// Runs a series of programs with one engine, each seeing the bindings and macros of those
+/// before it, as REPL lines do.
+pub struct Session {
+  state: State,
+  macro_env: Env,
+  division: Division,
+  compiler: CompilerOptions,
+  hooks: Hooks,
+}
+
+impl Session {
+  pub fn new(engine: Engine) -> Self {
+    let state = match engine {
+      Engine::Eval => State::Eval(Environment::new()),
+      Engine::Vm => {
+        let (symbol_table, constants) = Compiler::new().into_state();
+        State::Vm { symbol_table, constants, globals: vec!() }
+      },
+    };
+    Session {
+      state,
+      macro_env: Environment::new(),
+      division: Division::Truncate,
+      compiler: CompilerOptions::default(),
+      hooks: Hooks::default(),
+    }
+  }
+
+  pub fn with_options(options: Options) -> Self {
+    Session { division: options.division, compiler: options.compiler, ..Session::new(options.engine) }
+  }
+
+  /// Tells `observer` of the statements, calls and output of every program the session runs
+  /// from now on.
+  pub fn set_observer(&mut self, observer: SharedObserver) {
+    self.hooks.observer = Some(observer);
+  }
+
+  /// Passes every value the session's programs print, and every error message, through
+  /// `redactor`; `redact` does the same for other text.
+  pub fn set_redactor(&mut self, redactor: Redactor) {
+    self.hooks.redactor = Some(redactor);
+  }
+
+  pub fn redact(&self, text: &str) -> String {
+    self.hooks.redact(text)
+  }
+
+  fn redact_error(&self, e: MonkeyError) -> MonkeyError {
+    match e {
+      MonkeyError::Runtime { message, span } => {
+        MonkeyError::Runtime { message: self.redact(&message), span }
+      },
+      e => e,
+    }
+  }
+
+  /// Expands macros in the program and runs it. Only the VM can fail to compile; errors while
+  /// expanding or running are `MonkeyError::Runtime` with either engine.
+  pub fn run(&mut self, program: Program) -> Result<Object, MonkeyError> {
+    self.run_at(program, &[])
+  }
+
+  /// Runs the program as `run` does, given where each of its top-level statements starts, as
+  /// `Parser::statement_spans` lists them. An error while running then records where the
+  /// statement it stopped in starts.
+  pub fn run_at(&mut self, program: Program, spans: &[Span]) -> Result<Object, MonkeyError> {
+    // macro definitions are gone once the program is expanded, and so are their spans
+    let spans: Vec<Span> = program.statements.iter().zip(spans)
+      .filter(|(statement, _)| !macros::is_macro_definition(statement))
+      .map(|(_, span)| *span)
+      .collect();
+    let program = self.expand(program).map_err(|e| self.redact_error(e))?;
+    let program = match self.division {
+      Division::Truncate => program,
+      Division::Floor => floor_division(program),
+    };
+
+    // the index of the statement an error stopped in, if the engine knows it
+    let hooks = &self.hooks;
+    let result = match &mut self.state {
+      State::Eval(env) => observer::observing(hooks, &spans, || eval::eval_program(&program, env))
+        .map_err(|(i, e)| (Some(i), e)),
+      State::Vm { symbol_table, constants, globals } => {
+        let mut compiler = Compiler::new_with_state(mem::take(symbol_table), mem::take(constants))
+          .with_options(self.compiler);
+        let compiled = compiler.compile(&program);
+        let bytecode = compiler.bytecode();
+        let starts = compiler.statement_starts().to_vec();
+        let (table, pool) = compiler.into_state();
+        *symbol_table = table;
+        *constants = pool;
+        compiled?;
+
+        // compiling can run pure functions, which no observer should see
+        let mut vm = Vm::new_with_globals(bytecode, mem::take(globals))
+          .with_statement_starts(starts.clone());
+        let result = observer::observing(hooks, &spans, || vm.run()).map(|()| vm.last_popped());
+        let position = vm.position();
+        *globals = vm.into_globals();
+        result.map_err(|e| (starts.iter().rposition(|&start| start <= position), e))
+      },
+    };
+    result.map_err(|(statement, e)| match e {
+      MonkeyError::Runtime { message, span: None } => {
+        let span = statement.and_then(|i| spans.get(i).copied());
+        self.redact_error(MonkeyError::Runtime { message, span })
+      },
+      e => self.redact_error(e),
+    })
+  }
+
+  /// Notes the session's globals and macros, to go back to with `restore`.
+  pub fn checkpoint(&self) -> Checkpoint {
+    let state = match &self.state {
+      State::Eval(env) => Saved::Eval(env.borrow().bindings()),
+      State::Vm { symbol_table, constants, globals } => Saved::Vm {
+        symbol_table: symbol_table.clone(),
+        constants: constants.clone(),
+        globals: globals.clone(),
+      },
+    };
+    Checkpoint { state, macros: self.macro_env.borrow().bindings() }
+  }
+
+  /// Forgets every global and macro defined since the checkpoint, and undoes any assignment to
+  /// those it had. Functions defined before it still see its globals, as they did then.
+  pub fn restore(&mut self, checkpoint: &Checkpoint) {
+    match (&mut self.state, &checkpoint.state) {
+      (State::Eval(env), Saved::Eval(bindings)) => env.borrow_mut().restore(bindings.clone()),
+      (State::Vm { symbol_table, constants, globals }, Saved::Vm {
+        symbol_table: saved_table, constants: saved_constants, globals: saved_globals,
+      }) => {
+        *symbol_table = saved_table.clone();
+        *constants = saved_constants.clone();
+        *globals = saved_globals.clone();
+      },
+      _ => panic!("restoring a checkpoint from a session with another engine"),
+    }
+    self.macro_env.borrow_mut().restore(checkpoint.macros.clone());
+  }
+
+  /// Binds a global variable, as a top-level `let` would.
+  pub fn set_global(&mut self, name: &str, value: Object) {
+    match &mut self.state {
+      State::Eval(env) => env.borrow_mut().set(name, value),
+      State::Vm { symbol_table, globals, .. } => {
+        let index = symbol_table.define(name).index;
+        if index >= globals.len() {
+          globals.resize(index + 1, Object::Null);
+        }
+        globals[index] = value;
+      },
+    }
+  }
+
+  pub fn get_global(&mut self, name: &str) -> Option<Object> {
+    match &mut self.state {
+      State::Eval(env) => env.borrow().get(name),
+      State::Vm { symbol_table, globals, .. } => match symbol_table.resolve(name)? {
+        Symbol { scope: SymbolScope::Global, index, .. } => globals.get(index).cloned(),
+        _ => None,
+      },
+    }
+  }
+
+  /// Calls the function bound to the global `name`, as `name(args...)` in a program would.
+  pub fn call_function(&mut self, name: &str, args: Vec<Object>) -> Result<Object, MonkeyError> {
+    let hooks = self.hooks.clone();
+    let not_found = || MonkeyError::runtime(hooks.redact(&format!("identifier not found: {}", name)));
+    let function = match &mut self.state {
+      State::Eval(env) => eval::lookup_identifier(name, env).ok_or_else(not_found)?,
+      State::Vm { symbol_table, .. } => {
+        symbol_table.resolve(name).ok_or_else(not_found)?;
+        return self.call_compiled(name, args);
+      },
+    };
+    let result = observer::observing(&self.hooks, &[], || {
+      observer::function_called(&function, &args);
+      eval::apply_function(function, args)
+    });
+    match result {
+      Object::Error(message) => Err(self.redact_error(MonkeyError::runtime(message))),
+      result => Ok(result),
+    }
+  }
+
+  // The VM can only call functions from bytecode, so this compiles a call on arguments bound to
+  // globals that no script can name. The same globals serve every call, and are cleared after
+  // it so they don't keep the arguments alive.
+  fn call_compiled(&mut self, name: &str, args: Vec<Object>) -> Result<Object, MonkeyError> {
+    let names: Vec<String> = (0..args.len()).map(|i| format!("%arg{}", i)).collect();
+    for (name, arg) in names.iter().zip(args) {
+      self.set_global(name, arg);
+    }
+    let function = Box::new(Expression::Identifier(String::from(name)));
+    let arguments = names.iter().cloned().map(Expression::Identifier).collect();
+    let call = Statement::Expression(Expression::Call { function, arguments });
+    let result = self.run(Program { statements: vec!(call) });
+    for name in &names {
+      self.set_global(name, Object::Null);
+    }
+    result
+  }
+
+  /// Defines the program's macros for this and later programs, and returns it with every macro
+  /// call replaced by its expansion.
+  pub fn expand(&mut self, mut program: Program) -> Result<Program, MonkeyError> {
+    macros::define_macros(&mut program, &self.macro_env);
+    macros::expand_macros(program, &self.macro_env)
+  }
+}
+
+// Makes every `/` a `~/`, which only differs from it in rounding integer quotients down.
+fn floor_division(program: Program) -> Program {
+  let floored = ast::modify_program(program, &mut |expression| Ok::<_, Infallible>(match expression {
+    Expression::Infix(InfixOperator::Slash, left, right) =>
+      Expression::Infix(InfixOperator::TildeSlash, left, right),
+    expression => expression,
+  }));
+  floored.unwrap_or_else(|e| match e {})
+}
+
+/// Runs a whole script, writing any warnings and errors to `diagnostics` prefixed with `name`.
+/// Returns whether it ran without errors.
+pub fn run_script<W: Write>(
+  name: &str, source: &str, options: Options, mut diagnostics: W,
+) -> io::Result<bool> {
+  let (program, spans) = match parse_script(name, source, &mut diagnostics)? {
+    Some(parsed) => parsed,
+    None => return Ok(false),
+  };
+
+  for warning in lint::check_unused_values(&program) {
+    writeln!(diagnostics, "{}: warning: {}", name, warning)?;
+  }
+
+  match Session::with_options(options).run_at(program, &spans) {
+    Ok(_) => Ok(true),
+    Err(e) => {
+      write_error(name, &e, &mut diagnostics)?;
+      Ok(false)
+    },
+  }
+}
+
+/// Writes a script to `output` as it looks after macro expansion, one top-level statement per
+/// line. Errors go to `diagnostics` as they do for `run_script`.
+pub fn expand_script<W: Write, D: Write>(
+  name: &str, source: &str, mut output: W, mut diagnostics: D,
+) -> io::Result<bool> {
+  let program = match parse_script(name, source, &mut diagnostics)? {
+    Some((program, _)) => program,
+    None => return Ok(false),
+  };
+
+  // the engine doesn't matter, as macros always expand with the evaluator
+  match Session::new(Engine::Eval).expand(program) {
+    Ok(program) => {
+      for statement in &program.statements {
+        writeln!(output, "{}", statement)?;
+      }
+      Ok(true)
+    },
+    Err(e) => {
+      write_error(name, &e, &mut diagnostics)?;
+      Ok(false)
+    },
+  }
+}
+
+/// Writes a script to `output` as canonical source, as `fmt::format_program` prints it. Parse
+/// errors go to `diagnostics` as they do for `run_script`.
+pub fn format_script<W: Write, D: Write>(
+  name: &str, source: &str, mut output: W, mut diagnostics: D,
+) -> io::Result<bool> {
+  match parse_script(name, source, &mut diagnostics)? {
+    Some((program, _)) => {
+      write!(output, "{}", fmt::format_program(&program))?;
+      Ok(true)
+    },
+    None => Ok(false),
+  }
+}
+
+// The program, and where each of its top-level statements starts.
+fn parse_script<W: Write>(
+  name: &str, source: &str, diagnostics: &mut W,
+) -> io::Result<Option<(Program, Vec<Span>)>> {
+  let mut parser = Parser::new(Lexer::new(source));
+  match parser.parse_program() {
+    Ok(program) => Ok(Some((program, parser.statement_spans().to_vec()))),
+    Err(e) => {
+      write_error(name, &e, diagnostics)?;
+      Ok(None)
+    },
+  }
+}
+
+// Errors that know where they are go after the name as `name:line:column:...`, like a
+// compiler's.
+fn write_error<W: Write>(name: &str, error: &MonkeyError, diagnostics: &mut W) -> io::Result<()> {
+  match error {
+    MonkeyError::Syntax { .. } => {
+      for error in error.to_string().lines() {
+        writeln!(diagnostics, "{}:{}", name, error)?;
+      }
+      Ok(())
+    },
+    MonkeyError::Runtime { span: Some(_), .. } => writeln!(diagnostics, "{}:{}", name, error),
+    _ => writeln!(diagnostics, "{}: {}", name, error),
+  }
+}
+
+/// Expands any directories among `paths` into the `.monkey` files beneath them, in order.
+pub fn collect_scripts(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+  let mut scripts = vec!();
+  for path in paths {
+    if path.is_dir() {
+      let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+      entries.sort();
+      entries.retain(|p| p.is_dir() || p.extension().is_some_and(|e| e == "monkey"));
+      scripts.extend(collect_scripts(&entries)?);
+    } else {
+      scripts.push(path.clone());
+    }
+  }
+  Ok(scripts)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::parser;
+
+  fn run(source: &str, engine: Engine) -> (bool, String) {
+    let mut diagnostics = vec!();
+    let ok = run_script("test.monkey", source, Options::new(engine), &mut diagnostics).unwrap();
+    (ok, String::from_utf8(diagnostics).unwrap())
+  }
+
+  #[test]
+  fn test_run_script() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      assert_eq!(run("let x = 1;\nlet y = x + 1;\ny * 2\n", *engine), (true, String::new()));
+      assert_eq!(run("1 == 2;\n3", *engine), (true, String::from(
+        "test.monkey: warning: result of `==` comparison is unused\n"
+      )));
+      assert_eq!(run("let a = 1;\nlet b 2;\nlet = 3;", *engine), (false, String::from("\
+test.monkey:2:7: error[E0001]: expected next token to be Assign, got Int(2) instead
+test.monkey:3:5: error[E0002]: expected next token to be an identifier, got Assign instead
+")));
+      assert_eq!(run("1 +;\n@\n#", *engine), (false, String::from("\
+test.monkey:1:4: error[E0003]: no prefix parse function for Semicolon found
+test.monkey:2:1: error: unexpected character '@'
+test.monkey:3:1: error: unexpected character '#'
+")));
+      assert_eq!(run("let x = 1;\nx / 0;\n", *engine), (false, String::from(
+        "test.monkey:2:1: error: division by zero\n"
+      )));
+      let failing = "let twice = macro(x) { quote(unquote(x) * 2) };
+let half = fn(x) {
+  x / 0
+};
+
+let first = twice(half(1) + 1);";
+      assert_eq!(run(failing, *engine), (false, String::from(
+        "test.monkey:6:1: error: division by zero\n"
+      )));
+    }
+    assert_eq!(run("undefined", Engine::Vm), (false, String::from(
+      "test.monkey: compile error: undefined variable undefined\n"
+    )));
+  }
+
+  #[test]
+  fn test_floor_division() {
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+      let options = Options { division: Division::Floor, ..Options::new(*engine) };
+      let mut session = Session::with_options(options);
+      let program = parser::parse("[-7 / 2, 7 / -2, -7 ~/ 2, -7 / 2.0, -7 % 2]").unwrap();
+      assert_eq!(session.run(program).unwrap().to_string(), "[-4, -4, -4, -3.5, -1]");
+      let mut session = Session::new(*engine);
+      let program = parser::parse("[-7 / 2, -7 ~/ 2]").unwrap();
+      assert_eq!(session.run(program).unwrap().to_string(), "[-3, -4]");
+    }
+  }
+
+  #[test]
+  fn test_optimization_levels() {
+    let source = "let sq = fn(x) { x * x }; let f = fn() { sq(3) + 1 };\n[f(), 2 * 3]";
+    for level in 0..3 {
+      let options = Options { compiler: CompilerOptions::level(level), ..Options::new(Engine::Vm) };
+      let mut session = Session::with_options(options);
+      assert_eq!(session.run(parser::parse(source).unwrap()).unwrap().to_string(), "[10, 6]");
+      let error = session.run(parser::parse("1;\nf() / 0").unwrap()).unwrap_err();
+      assert_eq!(error.to_string(), "error: division by zero", "level {}", level);
+    }
+  }
+
+  #[test]
+  fn test_call_function() {
+    let mut session = Session::new(Engine::Vm);
+    session.run(parser::parse("let pair = fn(a, b) { [a, b] };").unwrap()).unwrap();
+    let result = session.call_function("pair", vec!(Object::Integer(1), Object::from("two")));
+    assert_eq!(result.unwrap().to_string(), "[1, two]");
+    // the arguments aren't kept once the call returns
+    assert_eq!(session.get_global("%arg1"), Some(Object::Null));
+    assert!(session.call_function("pair", vec!(Object::Integer(3))).is_err());
+    assert_eq!(session.get_global("%arg0"), Some(Object::Null));
+  }
+
+  #[test]
+  fn test_expand_script() {
+    let expand = |source: &str| {
+      let (mut output, mut diagnostics) = (vec!(), vec!());
+      let ok = expand_script("test.monkey", source, &mut output, &mut diagnostics).unwrap();
+      (ok, String::from_utf8(output).unwrap(), String::from_utf8(diagnostics).unwrap())
+    };
+    let source = "let twice = macro(x) { quote(unquote(x) + unquote(x)) };\nlet a = twice(1 * 2);\nputs(a)";
+    let expected = String::from("let a = ((1 * 2) + (1 * 2));\nputs(a)\n");
+    assert_eq!(expand(source), (true, expected, String::new()));
+    assert_eq!(expand("let m = macro() { 1 };\nm()"), (false, String::new(), String::from(
+      "test.monkey: error: macro `m` must return a QUOTE, got INTEGER\n"
+    )));
+  }
+
+  #[test]
+  fn test_format_script() {
+    let (mut output, mut diagnostics) = (vec!(), vec!());
+    assert!(format_script("test.monkey", "let f=fn(x){x*(1+2)}\nf(1)", &mut output, &mut diagnostics).unwrap());
+    assert_eq!(String::from_utf8(output).unwrap(), "let f = fn(x) { x * (1 + 2) };\nf(1);\n");
+    assert!(diagnostics.is_empty());
+  }
+
+  #[test]
+  fn test_collect_scripts() {
+    let dir = std::env::temp_dir().join(format!("monkey-collect-scripts-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("nested")).unwrap();
+    for file in ["b.monkey", "a.monkey", "notes.txt", "nested/c.monkey"].iter() {
+      fs::write(dir.join(file), "1").unwrap();
+    }
+
+    let explicit = dir.join("notes.txt");
+    let scripts = collect_scripts(&[dir.clone(), explicit.clone()]).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let expected = vec!(dir.join("a.monkey"), dir.join("b.monkey"), dir.join("nested/c.monkey"), explicit);
+    assert_eq!(scripts, expected);
+  }
+}
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::ast::*;
+
+/// Something the evaluator can run but the VM cannot.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Unsupported {
+  /// A macro that was never expanded, such as one outside a top-level let or in a program that
+  /// skipped expansion.
+  MacroLiteral(String),
+  Quote(String),
+  /// Closures in the VM hold copies of the variables they capture, so a captured variable can't
+  /// be rebound, whether by the closure or by the function it belongs to.
+  CapturedAssignment(String),
+}
+
+impl fmt::Display for Unsupported {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Unsupported::MacroLiteral(source) => write!(f, "unexpanded macro `{}`", source),
+      Unsupported::Quote(source) => write!(f, "`{}` outside a macro", source),
+      Unsupported::CapturedAssignment(name) => write!(f, "assignment to captured variable `{}`", name),
+    }
+  }
+}
+
+/// Lists everything in the program that the VM can't run, so the compiler can refuse it before
+/// compiling any of it. A variable that is captured and also assigned in its own function is
+/// listed when that function ends.
+pub fn unsupported_by_vm(program: &Program) -> Vec<Unsupported> {
+  let mut checker = Checker { functions: vec!(), found: vec!() };
+  checker.check_statements(&program.statements);
+  checker.found
+}
+
+// The variables a function defines itself; its own name is captured rather than local. Those
+// that closures capture and those it assigns itself may not overlap.
+struct Scope {
+  locals: HashSet<String>,
+  name: Option<String>,
+  captured: HashSet<String>,
+  assigned: Vec<String>,
+}
+
+struct Checker {
+  // Functions enclosing the current statement, innermost last; top-level names are globals.
+  functions: Vec<Scope>,
+  found: Vec<Unsupported>,
+}
+
+impl Checker {
+  fn check_statements(&mut self, statements: &[Statement]) {
+    for statement in statements {
+      match statement {
+        Statement::Let(name, Expression::FunctionLiteral { name: own_name, parameters, body }) => {
+          self.define(name);
+          self.check_function(parameters, body, Some(own_name.as_deref().unwrap_or(name)));
+        },
+        Statement::Let(name, value) => {
+          self.check_expression(value);
+          self.define(name);
+        },
+        Statement::Assign(name, value) => {
+          self.check_expression(value);
+          self.assign(name);
+        },
+        Statement::Return(e) | Statement::Expression(e) => self.check_expression(e),
+      }
+    }
+  }
+
+  fn define(&mut self, name: &str) {
+    if let Some(scope) = self.functions.last_mut() {
+      scope.locals.insert(String::from(name));
+    }
+  }
+
+  // The index of the function whose variable or own name `name` is, innermost first, or `None`
+  // for a global.
+  fn owner(&self, name: &str) -> Option<usize> {
+    self.functions.iter().rposition(|s| s.locals.contains(name) || s.name.as_deref() == Some(name))
+  }
+
+  fn capture(&mut self, name: &str) {
+    if let Some(owner) = self.owner(name).filter(|o| o + 1 < self.functions.len()) {
+      self.functions[owner].captured.insert(String::from(name));
+    }
+  }
+
+  fn assign(&mut self, name: &str) {
+    let innermost = self.functions.len().checked_sub(1);
+    match self.owner(name) {
+      Some(owner) if Some(owner) == innermost && self.functions[owner].locals.contains(name) => {
+        let assigned = &mut self.functions[owner].assigned;
+        if !assigned.iter().any(|a| a == name) {
+          assigned.push(String::from(name));
+        }
+      },
+      Some(_) => self.found.push(Unsupported::CapturedAssignment(String::from(name))),
+      None => {},
+    }
+  }
+
+  fn check_function(&mut self, parameters: &[String], body: &BlockStatement, name: Option<&str>) {
+    let locals = parameters.iter().cloned().collect();
+    let name = name.map(String::from);
+    self.functions.push(Scope { locals, name, captured: HashSet::new(), assigned: vec!() });
+    self.check_statements(&body.statements);
+    let Scope { captured, assigned, .. } = self.functions.pop().expect("function scope missing");
+    for name in assigned.into_iter().filter(|a| captured.contains(a)) {
+      self.found.push(Unsupported::CapturedAssignment(name));
+    }
+  }
+
+  fn check_expression(&mut self, expression: &Expression) {
+    match expression {
+      Expression::Identifier(name) => self.capture(name),
+      Expression::IntegerLiteral(_) | Expression::FloatLiteral(_) | Expression::StringLiteral(_)
+        | Expression::Boolean(_) => {},
+      Expression::ArrayLiteral(elements) => elements.iter().for_each(|e| self.check_expression(e)),
+      Expression::HashLiteral(pairs) => for (k, v) in pairs {
+        self.check_expression(k);
+        self.check_expression(v);
+      },
+      Expression::Prefix(_, right) => self.check_expression(right),
+      Expression::Infix(_, left, right) | Expression::Index { left, index: right } => {
+        self.check_expression(left);
+        self.check_expression(right);
+      },
+      Expression::Slice { left, start, end } => {
+        self.check_expression(left);
+        start.iter().chain(end).for_each(|b| self.check_expression(b));
+      },
+      Expression::If { condition, consequence, alternative } => {
+        self.check_expression(condition);
+        self.check_statements(&consequence.statements);
+        if let Some(alternative) = alternative {
+          self.check_statements(&alternative.statements);
+        }
+      },
+      Expression::While { condition, body } => {
+        self.check_expression(condition);
+        self.check_statements(&body.statements);
+      },
+      Expression::FunctionLiteral { name, parameters, body } =>
+        self.check_function(parameters, body, name.as_deref()),
+      Expression::MacroLiteral { .. } =>
+        self.found.push(Unsupported::MacroLiteral(expression.to_string())),
+      Expression::Call { function, .. } if **function == Expression::Identifier(String::from("quote")) =>
+        self.found.push(Unsupported::Quote(expression.to_string())),
+      Expression::Call { function, arguments } => {
+        self.check_expression(function);
+        arguments.iter().for_each(|a| self.check_expression(a));
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::lexer::Lexer;
+  use crate::parser::Parser;
+
+  fn unsupported(input: &str) -> Vec<String> {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    unsupported_by_vm(&program).iter().map(|u| u.to_string()).collect()
+  }
+
+  #[test]
+  fn test_unsupported_by_vm() {
+    assert_eq!(unsupported("let f = fn() { quote(1 + 2) }; [macro(x) { x }]"), vec!(
+      "`quote((1 + 2))` outside a macro",
+      "unexpanded macro `macro(x) { x }`",
+    ));
+    assert_eq!(unsupported("let f = fn() { let y = 1; fn() { y = 2 } }"), vec!(
+      "assignment to captured variable `y`",
+    ));
+    assert_eq!(unsupported("let f = fn(n) { f = n }"), vec!("assignment to captured variable `f`"));
+    assert_eq!(unsupported("let f = fn() { let n = 0; let g = fn() { n }; n = 5; g() }"), vec!(
+      "assignment to captured variable `n`",
+    ));
+    assert_eq!(unsupported("let f = fn(n) { n = n + 1; fn() { n } }"), vec!(
+      "assignment to captured variable `n`",
+    ));
+  }
+
+  #[test]
+  fn test_supported_by_vm() {
+    assert!(unsupported("let x = 1; let f = fn() { x = 2; let y = 3; y = 4 }; f()").is_empty());
+    assert!(unsupported("let f = fn(y) { fn(y) { y = 1 } }").is_empty());
+    assert!(unsupported("let f = fn() { let y = 1; while (y < 3) { y = y + 1 } }").is_empty());
+    assert!(unsupported("let f = fn() { let n = 0; n = 5; fn(n) { n } }").is_empty());
+  }
+}
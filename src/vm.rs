@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{InfixOperator, PrefixOperator};
+use crate::builtins;
+use crate::code::{read_operands, Instructions, OpCode};
+use crate::compiler::Bytecode;
+use crate::error::MonkeyError;
+use crate::eval;
+use crate::object::{Closure, CompiledFunction, HashPair, Object};
+use crate::observer;
+
+const STACK_SIZE: usize = 2048;
+const MAX_FRAMES: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmError(pub String);
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl Error for VmError {}
+
+type VmResult<T> = Result<T, VmError>;
+
+struct Frame {
+  closure: Rc<Closure>,
+  ip: usize,
+  base_pointer: usize,
+}
+
+/// Runs compiled bytecode on a stack machine. Operators and builtins behave as in the evaluator,
+/// with errors returned as `MonkeyError::Runtime` rather than as `Object::Error` values.
+pub struct Vm {
+  constants: Vec<Object>,
+  stack: Vec<Object>,
+  globals: Vec<Object>,
+  frames: Vec<Frame>,
+  last_popped: Option<Object>,
+  // where each top-level statement starts, and the first of them not yet reached
+  statement_starts: Vec<usize>,
+  next_statement: usize,
+}
+
+impl Vm {
+  pub fn new(bytecode: Bytecode) -> Self {
+    Vm::new_with_globals(bytecode, vec!())
+  }
+
+  /// Starts with the globals left behind by an earlier run (see `into_globals`).
+  pub fn new_with_globals(bytecode: Bytecode, globals: Vec<Object>) -> Self {
+    let main = CompiledFunction {
+      instructions: bytecode.instructions,
+      num_locals: 0,
+      num_parameters: 0,
+    };
+    let closure = Rc::new(Closure { function: Rc::new(main), free: vec!() });
+    Vm {
+      constants: bytecode.constants,
+      stack: Vec::with_capacity(STACK_SIZE),
+      globals,
+      frames: vec!(Frame { closure, ip: 0, base_pointer: 0 }),
+      last_popped: None,
+      statement_starts: vec!(),
+      next_statement: 0,
+    }
+  }
+
+  /// Tells the observer as the program reaches each of its top-level statements, given where
+  /// they start, as `Compiler::statement_starts` lists them.
+  pub fn with_statement_starts(self, statement_starts: Vec<usize>) -> Self {
+    Vm { statement_starts, ..self }
+  }
+
+  /// Where in the program's own instructions it is: at the instruction it stopped on, after an
+  /// error, or at the call that led there.
+  pub fn position(&self) -> usize {
+    self.frames[0].ip.saturating_sub(1)
+  }
+
+  pub fn into_globals(self) -> Vec<Object> {
+    self.globals
+  }
+
+  /// The value of the last expression statement run, which is the program's value.
+  pub fn last_popped(&self) -> Object {
+    self.last_popped.clone().unwrap_or(Object::Null)
+  }
+
+  pub fn run(&mut self) -> Result<(), MonkeyError> {
+    Ok(self.execute()?)
+  }
+
+  fn execute(&mut self) -> VmResult<()> {
+    while let Some((op, operands)) = self.fetch() {
+      match op {
+        OpCode::Constant => self.push(self.constants[operands[0]].clone())?,
+        OpCode::Pop => self.last_popped = Some(self.pop()),
+        OpCode::True => self.push(Object::Boolean(true))?,
+        OpCode::False => self.push(Object::Boolean(false))?,
+        OpCode::Null => self.push(Object::Null)?,
+        OpCode::Add
+          | OpCode::Sub
+          | OpCode::Mul
+          | OpCode::Div
+          | OpCode::FloorDiv
+          | OpCode::Mod
+          | OpCode::BitAnd
+          | OpCode::BitOr
+          | OpCode::BitXor
+          | OpCode::ShiftLeft
+          | OpCode::ShiftRight
+          | OpCode::Equal
+          | OpCode::NotEqual
+          | OpCode::GreaterThan
+          | OpCode::LessThan
+          | OpCode::GreaterEqual
+          | OpCode::LessEqual => {
+          let right = self.pop();
+          let left = self.pop();
+          let result = eval::eval_infix_expression(infix_operator(op), left, right);
+          self.push_result(result)?;
+        },
+        OpCode::Minus | OpCode::Bang => {
+          let operator = if op == OpCode::Minus { PrefixOperator::Minus } else { PrefixOperator::Bang };
+          let right = self.pop();
+          self.push_result(eval::eval_prefix_expression(operator, right))?;
+        },
+        OpCode::Jump => self.frame_mut().ip = operands[0],
+        OpCode::JumpNotTruthy => {
+          let condition = self.pop();
+          if !eval::is_truthy(&condition) {
+            self.frame_mut().ip = operands[0];
+          }
+        },
+        OpCode::SetGlobal => {
+          let value = self.pop();
+          let index = operands[0];
+          if index >= self.globals.len() {
+            self.globals.resize(index + 1, Object::Null);
+          }
+          self.globals[index] = value;
+        },
+        OpCode::GetGlobal => {
+          let value = self.globals.get(operands[0]).cloned().unwrap_or(Object::Null);
+          self.push(value)?;
+        },
+        OpCode::SetLocal => {
+          let value = self.pop();
+          let slot = self.frame().base_pointer + operands[0];
+          self.stack[slot] = value;
+        },
+        OpCode::GetLocal => {
+          let slot = self.frame().base_pointer + operands[0];
+          self.push(self.stack[slot].clone())?;
+        },
+        OpCode::GetBuiltin => {
+          let builtin = &builtins::BUILTINS[operands[0]];
+          self.push(Object::Builtin { name: String::from(builtin.name), function: builtin.function.into() })?;
+        },
+        OpCode::GetFree => {
+          let value = self.frame().closure.free[operands[0]].clone();
+          self.push(value)?;
+        },
+        OpCode::CurrentClosure => {
+          let closure = Rc::clone(&self.frame().closure);
+          self.push(Object::Closure(closure))?;
+        },
+        OpCode::Array => {
+          let elements = self.stack.split_off(self.stack.len() - operands[0]);
+          self.push(Object::Array(elements))?;
+        },
+        OpCode::Hash => {
+          let elements = self.stack.split_off(self.stack.len() - operands[0]);
+          let hash = build_hash(elements)?;
+          self.push(hash)?;
+        },
+        OpCode::Index => {
+          let index = self.pop();
+          let left = self.pop();
+          self.push_result(eval::eval_index_expression(left, index))?;
+        },
+        OpCode::Slice => {
+          let end = self.pop();
+          let start = self.pop();
+          let left = self.pop();
+          self.push_result(eval::eval_slice_expression(left, start, end))?;
+        },
+        OpCode::Call => self.call(operands[0])?,
+        OpCode::ReturnValue | OpCode::Return => {
+          let value = if op == OpCode::ReturnValue { self.pop() } else { Object::Null };
+          if self.frames.len() == 1 {
+            // a top-level return ends the program with its value
+            self.last_popped = Some(value);
+            return Ok(());
+          }
+          let frame = self.frames.pop().expect("returned without a frame");
+          self.stack.truncate(frame.base_pointer - 1);
+          self.push(value)?;
+        },
+        OpCode::Closure => {
+          let function = match &self.constants[operands[0]] {
+            Object::CompiledFunction(function) => Rc::clone(function),
+            other => return Err(VmError(format!("not a function: {}", other.type_name()))),
+          };
+          let free = self.stack.split_off(self.stack.len() - operands[1]);
+          self.push(Object::Closure(Rc::new(Closure { function, free })))?;
+        },
+      }
+    }
+    Ok(())
+  }
+
+  // Decodes the instruction at the current frame's instruction pointer and steps past it.
+  fn fetch(&mut self) -> Option<(OpCode, Vec<usize>)> {
+    if self.frames.len() == 1 {
+      let ip = self.frames[0].ip;
+      while self.statement_starts.get(self.next_statement).is_some_and(|&start| start <= ip) {
+        observer::statement(self.next_statement);
+        self.next_statement += 1;
+      }
+    }
+    let frame = self.frames.last_mut()?;
+    let Instructions(instructions) = &frame.closure.function.instructions;
+    let byte = *instructions.get(frame.ip)?;
+    let op = OpCode::from_byte(byte).unwrap_or_else(|| panic!("unknown opcode {}", byte));
+    let (operands, read) = read_operands(op, &instructions[frame.ip + 1..]);
+    frame.ip += 1 + read;
+    Some((op, operands))
+  }
+
+  fn call(&mut self, num_args: usize) -> VmResult<()> {
+    let callee_slot = self.stack.len() - 1 - num_args;
+    match self.stack[callee_slot].clone() {
+      Object::Closure(closure) => {
+        observer::function_called(&self.stack[callee_slot], &self.stack[callee_slot + 1..]);
+        let function = &closure.function;
+        if function.num_parameters != num_args {
+          return Err(VmError(format!(
+            "wrong number of arguments: want={}, got={}", function.num_parameters, num_args
+          )));
+        }
+        if self.frames.len() >= MAX_FRAMES {
+          return Err(VmError(String::from("stack overflow")));
+        }
+        let base_pointer = callee_slot + 1;
+        if base_pointer + function.num_locals > STACK_SIZE {
+          return Err(VmError(String::from("stack overflow")));
+        }
+        self.stack.resize(base_pointer + function.num_locals, Object::Null);
+        self.frames.push(Frame { closure, ip: 0, base_pointer });
+        Ok(())
+      },
+      Object::Builtin { function, .. } => {
+        let args = self.stack.split_off(callee_slot + 1);
+        let callee = self.stack.pop().expect("called without a callee");
+        observer::function_called(&callee, &args);
+        self.push_result(function.call(args))
+      },
+      other => Err(VmError(format!("not a function: {}", other.type_name()))),
+    }
+  }
+
+  fn frame(&self) -> &Frame {
+    self.frames.last().expect("no current frame")
+  }
+
+  fn frame_mut(&mut self) -> &mut Frame {
+    self.frames.last_mut().expect("no current frame")
+  }
+
+  fn push(&mut self, object: Object) -> VmResult<()> {
+    if self.stack.len() >= STACK_SIZE {
+      return Err(VmError(String::from("stack overflow")));
+    }
+    self.stack.push(object);
+    Ok(())
+  }
+
+  fn push_result(&mut self, object: Object) -> VmResult<()> {
+    match object {
+      Object::Error(message) => Err(VmError(message)),
+      object => self.push(object),
+    }
+  }
+
+  fn pop(&mut self) -> Object {
+    self.stack.pop().expect("stack underflow")
+  }
+}
+
+fn build_hash(elements: Vec<Object>) -> VmResult<Object> {
+  let mut hash = HashMap::with_capacity(elements.len() / 2);
+  let mut elements = elements.into_iter();
+  while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+    let hash_key = match key.hash_key() {
+      Some(k) => k,
+      None => return Err(VmError(format!("unusable as hash key: {}", key.type_name()))),
+    };
+    hash.insert(hash_key, HashPair { key, value });
+  }
+  Ok(Object::Hash(hash))
+}
+
+fn infix_operator(op: OpCode) -> InfixOperator {
+  match op {
+    OpCode::Add => InfixOperator::Plus,
+    OpCode::Sub => InfixOperator::Minus,
+    OpCode::Mul => InfixOperator::Asterisk,
+    OpCode::Div => InfixOperator::Slash,
+    OpCode::FloorDiv => InfixOperator::TildeSlash,
+    OpCode::Mod => InfixOperator::Percent,
+    OpCode::BitAnd => InfixOperator::Ampersand,
+    OpCode::BitOr => InfixOperator::Pipe,
+    OpCode::BitXor => InfixOperator::Caret,
+    OpCode::ShiftLeft => InfixOperator::ShiftLeft,
+    OpCode::ShiftRight => InfixOperator::ShiftRight,
+    OpCode::GreaterThan => InfixOperator::GT,
+    OpCode::LessThan => InfixOperator::LT,
+    OpCode::GreaterEqual => InfixOperator::GtEq,
+    OpCode::LessEqual => InfixOperator::LtEq,
+    OpCode::Equal => InfixOperator::Eq,
+    OpCode::NotEqual => InfixOperator::NotEq,
+    _ => unreachable!("{} is not an infix operator", op.name()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::compiler::{CompileError, Compiler, CompilerOptions};
+  use crate::eval::eval;
+  use crate::lexer::Lexer;
+  use crate::object::Environment;
+  use crate::parser::Parser;
+
+  fn run(input: &str) -> Result<Object, VmError> {
+    run_with(input, CompilerOptions::default())
+  }
+
+  fn run_with(input: &str, options: CompilerOptions) -> Result<Object, VmError> {
+    let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+    let mut compiler = Compiler::new().with_options(options);
+    compiler.compile(&program).map_err(vm_error)?;
+    let mut vm = Vm::new(compiler.bytecode());
+    vm.run().map_err(vm_error)?;
+    Ok(vm.last_popped())
+  }
+
+  // Compile errors and runtime errors are compared by their messages alone.
+  fn vm_error(e: MonkeyError) -> VmError {
+    match e {
+      MonkeyError::Compile(CompileError(message)) | MonkeyError::Runtime { message, .. } =>
+        VmError(message),
+      other => panic!("unexpected error {:?}", other),
+    }
+  }
+
+  // Both engines must agree, on values and on error messages.
+  #[test]
+  fn test_parity_with_evaluator() {
+    let cases = [
+      "1 + 2 * 3 - -4",
+      "7 / 2.0 + 1",
+      "!true == !!false",
+      "1 < 2 != 2 > 1",
+      "[-7 % 3, 6 & 3, 6 | 3, 6 ^ 3, 1 << 4, -16 >> 2, 7.5 % 2]",
+      "1 % 0",
+      "[-7 ~/ 2, 7 ~/ -2, 7.5 ~/ 2, -7 / 2]",
+      "7 ~/ 0",
+      "let f = fn(x) { if (x) { return 1; } else { return 2; } }; [f(true), f(false)]",
+      "let a = 1; let b = 0; [if (a) { if (b) { 1 } } else { 2 }, if (b) { 3 }]",
+      "let sq = fn(x) { x * x }; let f = fn(a, b) { if (a > b) { sq(a) } else { [a, b][1:] } }; [f(3, 2), f(1, 2)]",
+      "let f = fn(x) { x / 0 }; f(1)",
+      "let f = fn(n) { let i = 0; while (true) { if (i == n) { return i; } i = i + 1; } }; f(3)",
+      "[divmod(-7, 2), divmod(7, 0)]",
+      "let g = fn f(n) { if (n == 0) { 0 } else { f(n - 1) } }; let f = fn(n) { 99 }; [g(2), f(1)]",
+      "[fn fact(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }(5)]",
+      r#"let Point = struct { x, y }; let p = Point(1, 2); [p["x"], type(p), is_a(p, "Point")]"#,
+      "1 << 64",
+      "[1 <= 1, 2 >= 3, 1.5 >= 1, 0.0 / 0 <= 0.0 / 0]",
+      r#"[len("four") + first([1, 2]), rest(push([1], 2)), first([]), len(1)]"#,
+      r#"let len = fn(x) { 0 }; len("abc")"#,
+      "[true && false, 1 && 2, if (false) { 1 } || 0, false || false]",
+      "let n = 0; let bump = fn() { n = n + 1; true }; let r = false && bump() || bump(); [r, n]",
+      "true && -true",
+      r#""mon" + "key""#,
+      "if (1 > 2) { 10 } else { 20 }",
+      "if (false) { 10 }",
+      "if (true) { let x = 1; }",
+      "while (false) { 1 }",
+      "let i = 0; let sum = 0; while (i < 5) { i = i + 1; sum = sum + i; } sum",
+      "let f = fn(n) { let total = 0; while (n > 0) { total = total + n; n = n - 1; } total }; f(4)",
+      "let x = 1; let f = fn() { x = 5 }; f(); x",
+      "y = 1",
+      "let f = fn(x) { while (x) { let y = 2; return y; } }; [f(true), f(false)]",
+      "if (1 < 2) { return -(1 + 2) * 3; 4 } 5",
+      "let f = fn() { if (!true) { 1 } else { return 2; 3 } }; f() + 1",
+      "let a = 5; let b = a * 2; a + b",
+      "let a = 1; let a = a + 1; a",
+      "[1, 2 * 2, 3][1]",
+      r#"let s = "hello"; [s[1], s[9], s[1:3], s[:2], s[3:], s[4:2], [1, 2, 3][1:]]"#,
+      r#""hello"["a":]"#,
+      r#"join(split(to_upper(trim(" a,b ")), ","), "-") + chars("xy")[1]"#,
+      "[1, 2, 3][3]",
+      r#"{"one": 1, 2: "two", true: 3}[2]"#,
+      r#"let h = {"a": 1}; h["b"]"#,
+      "let add = fn(a, b) { a + b }; add(1, add(2, 3))",
+      "let f = fn() { }; f()",
+      "let f = fn() { let x = 1; }; f()",
+      "let f = fn() { return 1; 2 }; f()",
+      "9; return 2 * 5; 9;",
+      "let new_adder = fn(x) { fn(y) { x + y } }; let add_two = new_adder(2); add_two(3)",
+      "let add = fn(a) { fn(b) { fn(c) { a + b + c } } }; add(1)(2)(3)",
+      "let fact = fn(n) { if (n < 2) { return 1; } n * fact(n - 1) }; fact(10)",
+      "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+      "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+       let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+       even(10)",
+      "let outer = fn() { let inner = fn(n) { if (n == 0) { 0 } else { inner(n - 1) } }; inner(3) }; outer()",
+      "let counter = fn(n) { [n, fn() { counter(n + 1) }] }; counter(0)[1]()[1]()[0]",
+      "len([1, 2, 3]) + len(\"four\")",
+      "rest(push([1, 2], 3))",
+      "first([])",
+      "5 + true; 5;",
+      "-true",
+      "5 / 0",
+      "9223372036854775807 + 1",
+      "5(1)",
+      "fn(x) { x }()",
+      "len(1)",
+      "1[0]",
+      "{[1]: 2}",
+      r#"{"a": 1}[fn(x) { x }]"#,
+    ];
+    for input in cases.iter() {
+      let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+      let expected = eval(&program, &Environment::new()).map_err(vm_error);
+      assert_eq!(run(input), expected, "input {:?}", input);
+      let optimized = run_with(input, CompilerOptions { optimize: true, evaluate_calls: true });
+      assert_eq!(optimized, expected, "optimized input {:?}", input);
+    }
+
+    // Closures in the VM copy what they capture, so where the engines would disagree, the VM
+    // must refuse the program rather than run it.
+    let refused = [
+      ("let f = fn() { let n = 0; let g = fn() { n }; n = 5; g() }; f()", "n"),
+      ("let f = fn(n) { let g = fn() { n }; n = n + 1; g() }; f(1)", "n"),
+    ];
+    for (input, name) in refused.iter() {
+      let program = Parser::new(Lexer::new(input)).parse_program().unwrap();
+      assert!(eval(&program, &Environment::new()).is_ok(), "input {:?}", input);
+      let message = format!("unsupported by engine vm: assignment to captured variable `{}`", name);
+      assert_eq!(run(input), Err(VmError(message)), "input {:?}", input);
+    }
+  }
+
+  #[test]
+  fn test_undefined_variables_fail_to_compile() {
+    let input = "let f = fn() { later }; let later = 7; f()";
+    assert_eq!(run(input), Err(VmError(String::from("undefined variable later"))));
+    let input = "let count = fn() { let n = 0; fn() { n = n + 1; n } }";
+    let message = "unsupported by engine vm: assignment to captured variable `n`";
+    assert_eq!(run(input), Err(VmError(String::from(message))));
+  }
+
+  #[test]
+  fn test_unbounded_recursion_overflows() {
+    assert_eq!(run("let f = fn(n) { f(n + 1) }; f(0)"), Err(VmError(String::from("stack overflow"))));
+  }
+
+  #[test]
+  fn test_globals_carry_over() {
+    let mut compiler = Compiler::new();
+    compiler.compile(&Parser::new(Lexer::new("let x = 40;")).parse_program().unwrap()).unwrap();
+    let mut vm = Vm::new(compiler.bytecode());
+    vm.run().unwrap();
+    let globals = vm.into_globals();
+
+    let (symbol_table, constants) = compiler.into_state();
+    let mut compiler = Compiler::new_with_state(symbol_table, constants);
+    compiler.compile(&Parser::new(Lexer::new("x + 2")).parse_program().unwrap()).unwrap();
+    let mut vm = Vm::new_with_globals(compiler.bytecode(), globals);
+    vm.run().unwrap();
+    assert_eq!(vm.last_popped(), Object::Integer(42));
+  }
+}